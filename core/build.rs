@@ -7,6 +7,7 @@ use std::env;
 use std::path::PathBuf;
 use std::fs::OpenOptions;
 use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 fn main() {
     let out = PathBuf::from(env::var("OUT_DIR").unwrap());
@@ -36,6 +37,18 @@ pub fn build_id() -> &'static str {{
         println!("{}", e);
     }
 
+    let build_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let build_time_fn = format!("
+/// Seconds since the epoch at which this build was made.
+pub fn build_time() -> u64 {{
+    {}
+}}
+", build_time);
+
+    if let Err(e) = version_file.write_all(build_time_fn.as_bytes()) {
+        println!("{}", e);
+    }
+
     protobuf_macros::expand("src/lib.in.rs", &out.join("lib.rs")).unwrap();
 
     println!("cargo:rerun-if-changed=src/lib.in.rs");
@@ -10,6 +10,8 @@ use protobuf::ProtobufEnum;
 use rpassword;
 use serde;
 use serde_json;
+use std::error::Error;
+use std::fmt;
 use std::io::{self, stderr, Read, Write};
 use std::fs::File;
 use std::path::Path;
@@ -143,6 +145,29 @@ impl Credentials {
     }
 }
 
+/// Carried inside the `io::Error` from logging in when Spotify turned the credentials down,
+/// so callers can tell it apart from a connection failing with the same error kind
+#[derive(Debug)]
+pub struct CredentialsRejected(pub String);
+
+impl fmt::Display for CredentialsRejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Authentication failed: {}", self.0)
+    }
+}
+
+impl Error for CredentialsRejected {
+    fn description(&self) -> &str {
+        "Spotify rejected the credentials"
+    }
+}
+
+impl CredentialsRejected {
+    pub fn is(err: &io::Error) -> bool {
+        err.get_ref().map_or(false, |inner| inner.is::<CredentialsRejected>())
+    }
+}
+
 fn serialize_protobuf_enum<T, S>(v: &T, ser: S) -> Result<S::Ok, S::Error>
     where T: ProtobufEnum, S: serde::Serializer {
 
@@ -169,6 +194,58 @@ fn deserialize_base64<D>(de: D) -> Result<Vec<u8>, D::Error>
     base64::decode(&v).map_err(|e| serde::de::Error::custom(e.to_string()))
 }
 
+#[derive(Deserialize)]
+struct CredentialsFile {
+    username: String,
+    password: String,
+}
+
+/// Reads a username and password from `path`, either as a JSON object with `username` and
+/// `password` fields or as two lines. Files readable by other users are refused.
+pub fn read_credentials_file<P: AsRef<Path>>(path: P) -> io::Result<(String, String)> {
+    let path = path.as_ref();
+    let mut file = try!(File::open(path));
+    try!(check_private(&file, path));
+
+    let mut contents = String::new();
+    try!(file.read_to_string(&mut contents));
+
+    if contents.trim_left().starts_with('{') {
+        let parsed: CredentialsFile = try!(serde_json::from_str(&contents).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), err))
+        }));
+        return Ok((parsed.username, parsed.password));
+    }
+
+    let mut lines = contents.lines();
+    match (lines.next(), lines.next()) {
+        (Some(username), Some(password)) if !username.is_empty() => {
+            Ok((username.to_owned(), password.to_owned()))
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                format!("{}: expected a username line and a password line",
+                                        path.display()))),
+    }
+}
+
+#[cfg(unix)]
+fn check_private(file: &File, path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = try!(file.metadata()).permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                  format!("{} is accessible by other users (mode {:o}), run chmod 600 on it",
+                                          path.display(), mode & 0o777)));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_private(_file: &File, _path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
 pub fn get_credentials(username: Option<String>, password: Option<String>,
                        cached_credentials: Option<Credentials>)
     -> Option<Credentials>
@@ -1,31 +1,73 @@
-use std::path::PathBuf;
-use std::io::Read;
-use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::io::{self, Read, Write};
+use std::fs::{self, File};
 
 use util::{FileId, mkdir_existing};
 use authentication::Credentials;
 
+/// Where each kind of cached data lives under a cache directory, without creating anything
+#[derive(Serialize, Debug)]
+pub struct CachePaths {
+    pub root: PathBuf,
+    pub credentials: PathBuf,
+    pub accounts: PathBuf,
+    pub files: PathBuf,
+    pub scrobbler_state: PathBuf,
+}
+
+impl CachePaths {
+    pub fn new(location: &Path) -> CachePaths {
+        CachePaths {
+            root: location.to_owned(),
+            credentials: location.join("credentials.json"),
+            accounts: location.join("accounts"),
+            files: location.join("files"),
+            scrobbler_state: location.join("scrobbler.json"),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Cache {
     root: PathBuf,
     use_audio_cache: bool,
+    read_only: bool,
 }
 
 impl Cache {
-    pub fn new(location: PathBuf, use_audio_cache: bool) -> Cache {
-        mkdir_existing(&location).unwrap();
-        mkdir_existing(&location.join("files")).unwrap();
+    /// Creates the cache directory (and any missing parents), checking it can be written to
+    pub fn new(location: PathBuf, use_audio_cache: bool) -> io::Result<Cache> {
+        try!(fs::create_dir_all(&CachePaths::new(&location).files));
+        try!(fs::create_dir_all(&CachePaths::new(&location).accounts));
 
-        Cache {
+        let probe = location.join(".write-test");
+        try!(File::create(&probe));
+        try!(fs::remove_file(&probe));
+
+        Ok(Cache {
             root: location,
-            use_audio_cache: use_audio_cache
+            use_audio_cache: use_audio_cache,
+            read_only: false,
+        })
+    }
+
+    /// Opens an existing, pre-seeded cache that is only ever read from, for read-only filesystems
+    pub fn read_only(location: PathBuf) -> io::Result<Cache> {
+        if !try!(fs::metadata(&location)).is_dir() {
+            return Err(io::Error::new(io::ErrorKind::Other, "not a directory"))
         }
+
+        Ok(Cache {
+            root: location,
+            use_audio_cache: false,
+            read_only: true,
+        })
     }
 }
 
 impl Cache {
     fn credentials_path(&self) -> PathBuf {
-        self.root.join("credentials.json")
+        CachePaths::new(&self.root).credentials
     }
 
     pub fn credentials(&self) -> Option<Credentials> {
@@ -33,16 +75,74 @@ impl Cache {
         Credentials::from_file(path)
     }
 
+    /// Usernames can hold any character, so account files are named by the hex of the username
+    fn account_path(&self, username: &str) -> PathBuf {
+        let name = username.bytes().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        CachePaths::new(&self.root).accounts.join(name + ".json")
+    }
+
+    pub fn account_credentials(&self, username: &str) -> Option<Credentials> {
+        Credentials::from_file(self.account_path(username))
+    }
+
+    pub fn cached_usernames(&self) -> Vec<String> {
+        let entries = match fs::read_dir(CachePaths::new(&self.root).accounts) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new()
+        };
+
+        let mut usernames = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| Credentials::from_file(entry.path()))
+            .map(|credentials| credentials.username)
+            .collect::<Vec<_>>();
+        usernames.sort();
+        usernames
+    }
+
+    /// Saves the credentials as the ones to use by default, and under their account
     pub fn save_credentials(&self, cred: &Credentials) {
+        if self.read_only {
+            return
+        }
+
         let path = self.credentials_path();
         cred.save_to_file(&path);
+        cred.save_to_file(self.account_path(&cred.username));
+    }
+}
+
+impl Cache {
+    fn scrobbler_state_path(&self) -> PathBuf {
+        CachePaths::new(&self.root).scrobbler_state
+    }
+
+    pub fn scrobbler_state(&self) -> Option<String> {
+        let mut contents = String::new();
+        File::open(self.scrobbler_state_path()).ok()
+            .and_then(|mut file| file.read_to_string(&mut contents).ok())
+            .map(|_| contents)
+    }
+
+    pub fn save_scrobbler_state(&self, contents: &str) {
+        if self.read_only {
+            return
+        }
+
+        let result = File::create(self.scrobbler_state_path()).and_then(|mut file| {
+            file.write_all(contents.as_bytes())
+        });
+
+        if let Err(err) = result {
+            warn!("Could not save scrobbler state: {}", err);
+        }
     }
 }
 
 impl Cache {
     fn file_path(&self, file: FileId) -> PathBuf {
         let name = file.to_base16();
-        self.root.join("files").join(&name[0..2]).join(&name[2..])
+        CachePaths::new(&self.root).files.join(&name[0..2]).join(&name[2..])
     }
 
     pub fn file(&self, file: FileId) -> Option<File> {
@@ -50,7 +150,7 @@ impl Cache {
     }
 
     pub fn save_file(&self, file: FileId, contents: &mut Read) {
-        if self.use_audio_cache {
+        if self.use_audio_cache && !self.read_only {
             let path = self.file_path(file);
 
             mkdir_existing(path.parent().unwrap()).unwrap();
@@ -8,6 +8,8 @@ use version;
 pub struct SessionConfig {
     pub user_agent: String,
     pub device_id: String,
+    /// Used for track availability instead of the country Spotify reports for the account
+    pub country: Option<String>,
 }
 
 impl Default for SessionConfig {
@@ -16,6 +18,7 @@ impl Default for SessionConfig {
         SessionConfig {
             user_agent: version::version_string(),
             device_id: device_id,
+            country: None,
         }
     }
 }
@@ -79,4 +82,6 @@ impl Default for DeviceType {
 pub struct ConnectConfig {
     pub name: String,
     pub device_type: DeviceType,
+    pub trace_events: bool,
+    pub initial_volume: u16,
 }
@@ -12,7 +12,7 @@ use tokio_core::reactor::Handle;
 use tokio_core::io::Framed;
 use protobuf::{self, Message};
 
-use authentication::Credentials;
+use authentication::{Credentials, CredentialsRejected};
 use version;
 
 pub type Transport = Framed<TcpStream, APCodec>;
@@ -31,6 +31,7 @@ pub fn authenticate(transport: Transport, credentials: Credentials, device_id: S
     -> BoxFuture<(Transport, Credentials), io::Error>
 {
     use protocol::authentication::{APWelcome, ClientResponseEncrypted, CpuFamily, Os};
+    use protocol::keyexchange::{APLoginFailed, ErrorCode};
 
     let packet = protobuf_init!(ClientResponseEncrypted::new(), {
         login_credentials => {
@@ -67,9 +68,24 @@ pub fn authenticate(transport: Transport, credentials: Credentials, device_id: S
                 Ok((transport, reusable_credentials))
             }
 
-            Some((0xad, _)) => panic!("Authentication failed"),
-            Some((cmd, _)) => panic!("Unexpected packet {:?}", cmd),
-            None => panic!("EOF"),
+            Some((0xad, data)) => {
+                let error_code = protobuf::parse_from_bytes::<APLoginFailed>(data.as_ref())
+                    .map(|failed| failed.get_error_code())
+                    .unwrap_or(ErrorCode::ProtocolError);
+
+                // Rejected credentials need fixing, unlike the server asking us to come back later
+                match error_code {
+                    ErrorCode::BadCredentials | ErrorCode::CouldNotValidateCredentials => {
+                        let rejected = CredentialsRejected(format!("{:?}", error_code));
+                        Err(io::Error::new(io::ErrorKind::PermissionDenied, rejected))
+                    }
+                    _ => Err(io::Error::new(io::ErrorKind::Other, format!("Authentication failed: {:?}", error_code))),
+                }
+            }
+            Some((cmd, _)) => {
+                Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected packet {:?}", cmd)))
+            }
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed during login")),
         }
     }).boxed()
 }
@@ -136,6 +136,14 @@ impl MercuryManager {
         }).boxed()
     }
 
+    /// Drops every pending request and subscription, which fails the former and ends the latter
+    pub fn shutdown(&self) {
+        self.lock(|inner| {
+            inner.pending.clear();
+            inner.subscriptions.clear();
+        });
+    }
+
     pub fn dispatch(&self, cmd: u8, mut data: EasyBuf) {
         let seq_len = BigEndian::read_u16(data.drain_to(2).as_ref()) as usize;
         let seq = data.drain_to(seq_len).as_ref().to_owned();
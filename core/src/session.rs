@@ -80,7 +80,7 @@ impl Session {
                 &handle, transport, config, cache, reusable_credentials.username.clone()
             );
 
-            handle.spawn(task.map_err(|e| panic!(e)));
+            handle.spawn(task.map_err(|e| error!("Connection to Spotify lost: {}", e)));
 
             session
         });
@@ -99,10 +99,11 @@ impl Session {
 
         debug!("new Session[{}]", session_id);
 
+        let country = config.country.clone().unwrap_or_default();
         let session = Session(Arc::new(SessionInternal {
             config: config,
             data: RwLock::new(SessionData {
-                country: String::new(),
+                country: country,
                 canonical_username: username,
             }),
 
@@ -120,7 +121,7 @@ impl Session {
         }));
 
         let sender_task = sender_rx
-            .map_err(|e| -> io::Error { panic!(e) })
+            .map_err(|()| io::Error::new(io::ErrorKind::Other, "session packet queue closed"))
             .forward(sink).map(|_| ());
         let receiver_task = DispatchTask(stream, session.weak());
 
@@ -165,8 +166,13 @@ impl Session {
             0x4a => (),
             0x1b => {
                 let country = String::from_utf8(data.as_ref().to_owned()).unwrap();
-                info!("Country: {:?}", country);
-                self.0.data.write().unwrap().country = country;
+                match self.config().country {
+                    Some(ref forced) => info!("Country: {:?}, using {:?} instead", country, forced),
+                    None => {
+                        info!("Country: {:?}", country);
+                        self.0.data.write().unwrap().country = country;
+                    }
+                }
             }
 
             0x9 | 0xa => self.channel().dispatch(cmd, data),
@@ -177,7 +183,16 @@ impl Session {
     }
 
     pub fn send_packet(&self, cmd: u8, data: Vec<u8>) {
-        self.0.tx_connection.send((cmd, data)).unwrap();
+        if self.0.tx_connection.send((cmd, data)).is_err() {
+            debug!("Session[{}] dropped a packet, the connection is closed", self.0.session_id);
+        }
+    }
+
+    /// Called once the connection is gone, so that pending requests fail and subscriptions end
+    /// instead of waiting forever
+    pub fn shutdown(&self) {
+        debug!("Session[{}] shutting down", self.0.session_id);
+        self.mercury().shutdown();
     }
 
     pub fn cache(&self) -> Option<&Arc<Cache>> {
@@ -232,10 +247,10 @@ struct DispatchTask<S>(S, SessionWeak)
     where S: Stream<Item = (u8, EasyBuf)>;
 
 impl <S> Future for DispatchTask<S>
-    where S: Stream<Item = (u8, EasyBuf)>
+    where S: Stream<Item = (u8, EasyBuf), Error = io::Error>
 {
     type Item = ();
-    type Error = S::Error;
+    type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         let session = match self.1.try_upgrade() {
@@ -246,7 +261,18 @@ impl <S> Future for DispatchTask<S>
         };
 
         loop {
-            let (cmd, data) = try_ready!(self.0.poll()).expect("connection closed");
+            let (cmd, data) = match self.0.poll() {
+                Ok(Async::Ready(Some(packet))) => packet,
+                Ok(Async::Ready(None)) => {
+                    session.shutdown();
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"))
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => {
+                    session.shutdown();
+                    return Err(err)
+                }
+            };
             session.dispatch(cmd, data);
         }
     }
@@ -259,3 +285,18 @@ impl <S> Drop for DispatchTask<S>
         debug!("drop Dispatch");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::device_id;
+
+    #[test]
+    fn device_id_is_stable_for_a_name() {
+        assert_eq!(device_id("Scrobbler"), device_id("Scrobbler"));
+    }
+
+    #[test]
+    fn device_id_differs_between_names() {
+        assert!(device_id("Scrobbler") != device_id("Living Room"));
+    }
+}
@@ -64,6 +64,26 @@ impl SpotifyId {
         std::str::from_utf8(&data).unwrap().to_owned()
     }
 
+    pub fn to_base62(&self) -> String {
+        let &SpotifyId(ref n) = self;
+        let (high, low) = n.parts();
+        let mut limbs = [(high >> 32) as u32, high as u32, (low >> 32) as u32, low as u32];
+
+        // Long division by 62 a limb at a time, which yields the least significant digit first
+        let mut data = [0u8; 22];
+        for i in 0..22 {
+            let mut rem = 0u64;
+            for limb in limbs.iter_mut() {
+                let acc = (rem << 32) | *limb as u64;
+                *limb = (acc / 62) as u32;
+                rem = acc % 62;
+            }
+            data[21 - i] = BASE62_DIGITS[rem as usize];
+        }
+
+        std::str::from_utf8(&data).unwrap().to_owned()
+    }
+
     pub fn to_raw(&self) -> [u8; 16] {
         let &SpotifyId(ref n) = self;
         let (high, low) = n.parts();
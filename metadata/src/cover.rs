@@ -17,3 +17,8 @@ pub fn get(session: &Session, file: FileId) -> ChannelData {
 
     data
 }
+
+/// Public CDN address of a cover image, for consumers outside the Spotify session
+pub fn url(file: FileId) -> String {
+    format!("https://i.scdn.co/image/{}", file.to_base16())
+}
@@ -16,6 +16,7 @@ use core::session::Session;
 use core::util::{SpotifyId, FileId, StrChunksExt};
 
 pub use protocol::metadata::AudioFile_Format as FileFormat;
+pub use protocol::metadata::Image_Size as ImageSize;
 
 fn countrylist_contains(list: &str, country: &str) -> bool {
     list.chunks(2).any(|cc| cc == country)
@@ -75,6 +76,7 @@ pub trait Metadata : Send + Sized + 'static {
 pub struct Track {
     pub id: SpotifyId,
     pub name: String,
+    pub duration: i32,
     pub album: SpotifyId,
     pub artists: Vec<SpotifyId>,
     pub files: LinearMap<FileFormat, FileId>,
@@ -89,6 +91,7 @@ pub struct Album {
     pub artists: Vec<SpotifyId>,
     pub tracks: Vec<SpotifyId>,
     pub covers: Vec<FileId>,
+    pub cover_sizes: Vec<(ImageSize, FileId)>,
 }
 
 #[derive(Debug, Clone)]
@@ -127,6 +130,7 @@ impl Metadata for Track {
         Track {
             id: SpotifyId::from_raw(msg.get_gid()),
             name: msg.get_name().to_owned(),
+            duration: msg.get_duration(),
             album: SpotifyId::from_raw(msg.get_album().get_gid()),
             artists: artists,
             files: files,
@@ -162,16 +166,17 @@ impl Metadata for Album {
                         .map(|track| SpotifyId::from_raw(track.get_gid()))
                         .collect::<Vec<_>>();
 
-        let covers = msg.get_cover_group()
+        let cover_sizes = msg.get_cover_group()
                         .get_image()
                         .iter()
                         .filter(|image| image.has_file_id())
                         .map(|image| {
                             let mut dst = [0u8; 20];
                             dst.clone_from_slice(image.get_file_id());
-                            FileId(dst)
+                            (image.get_size(), FileId(dst))
                         })
                         .collect::<Vec<_>>();
+        let covers = cover_sizes.iter().map(|&(_, file_id)| file_id).collect::<Vec<_>>();
 
         Album {
             id: SpotifyId::from_raw(msg.get_gid()),
@@ -179,6 +184,7 @@ impl Metadata for Album {
             artists: artists,
             tracks: tracks,
             covers: covers,
+            cover_sizes: cover_sizes,
         }
     }
 }
@@ -0,0 +1,212 @@
+use futures::sync::mpsc;
+use futures::{future, Future, Stream};
+use hyper::{self, Method, StatusCode};
+use hyper::header::{ContentLength, ContentType};
+use hyper::server::{Http, Request, Response, Service};
+use serde_json;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio_core::reactor::Handle;
+
+use core::util::now_ms;
+use scrobbler::ScrobblerStats;
+
+#[derive(Debug)]
+pub enum ControlCommand {
+    Play,
+    Pause,
+    Next,
+    Prev,
+    Volume(u16),
+}
+
+#[derive(Serialize, Debug)]
+struct Status {
+    uptime_secs: u64,
+    connected: bool,
+    active: bool,
+    playing: bool,
+    track: Option<String>,
+    cover_url: Option<String>,
+    listening_session: Option<u64>,
+    position_ms: u64,
+    scrobbles: u64,
+    last_scrobble_secs_ago: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+struct Health {
+    connected: bool,
+    scrobbling: bool,
+}
+
+#[derive(Clone)]
+struct ControlService {
+    stats: Arc<Mutex<ScrobblerStats>>,
+    commands: mpsc::UnboundedSender<ControlCommand>,
+    secret: Option<String>,
+}
+
+/// The current state as served by `GET /status`
+pub fn status_json(stats: &Mutex<ScrobblerStats>) -> String {
+    serde_json::to_string(&status(&stats.lock().unwrap())).unwrap()
+}
+
+fn status(stats: &ScrobblerStats) -> Status {
+    // The reported position is a snapshot, so advance it by the time spent playing since
+    let mut position_ms = stats.position_ms as u64;
+    if stats.playing && stats.position_measured_at > 0 {
+        position_ms += (now_ms() as u64).saturating_sub(stats.position_measured_at);
+    }
+
+    Status {
+        uptime_secs: stats.started_at.map_or(0, |started_at| started_at.elapsed().as_secs()),
+        connected: stats.connected,
+        active: stats.active,
+        playing: stats.playing,
+        track: stats.current_track.map(|track_id| track_id.to_base16()),
+        cover_url: stats.current_cover_url.clone(),
+        listening_session: stats.listening_session,
+        position_ms: position_ms,
+        scrobbles: stats.scrobbles,
+        last_scrobble_secs_ago: stats.last_scrobble.map(|last| last.elapsed().as_secs()),
+    }
+}
+
+impl ControlService {
+    /// Healthy while connected to Spotify and not cut off by Last.fm
+    fn health(&self) -> Response {
+        let health = {
+            let stats = self.stats.lock().unwrap();
+            Health {
+                connected: stats.connected,
+                scrobbling: !stats.lastfm_failed,
+            }
+        };
+
+        let status = if health.connected && health.scrobbling {
+            StatusCode::Ok
+        } else {
+            StatusCode::ServiceUnavailable
+        };
+        json_response(serde_json::to_string(&health).unwrap()).with_status(status)
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        match self.secret {
+            Some(ref secret) => {
+                request.headers().get_raw("X-Control-Secret")
+                    .and_then(|header| header.one())
+                    .map_or(false, |value| value == secret.as_bytes())
+            }
+            None => true,
+        }
+    }
+
+    fn command(&self, request: &Request, command: ControlCommand) -> Response {
+        if !self.authorized(request) {
+            return Response::new().with_status(StatusCode::Unauthorized);
+        }
+
+        let active = {
+            let stats = self.stats.lock().unwrap();
+            stats.connected && stats.active
+        };
+        if !active {
+            return Response::new().with_status(StatusCode::ServiceUnavailable);
+        }
+
+        match mpsc::UnboundedSender::send(&self.commands, command) {
+            Ok(()) => Response::new().with_status(StatusCode::NoContent),
+            Err(_) => Response::new().with_status(StatusCode::ServiceUnavailable),
+        }
+    }
+}
+
+/// Parses `value=N` (0-100) from a `/volume` query string into a Spirc volume
+fn parse_volume(query: Option<&str>) -> Option<u16> {
+    let value = query.and_then(|query| {
+        query.split('&')
+            .filter_map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some("value"), Some(value)) => Some(value),
+                    _ => None,
+                }
+            })
+            .next()
+    });
+
+    match value.and_then(|value| value.parse::<u32>().ok()) {
+        Some(percent) if percent <= 100 => Some((percent * 0xFFFF / 100) as u16),
+        _ => None,
+    }
+}
+
+fn json_response(body: String) -> Response {
+    Response::new()
+        .with_header(ContentType::json())
+        .with_header(ContentLength(body.len() as u64))
+        .with_body(body)
+}
+
+impl Service for ControlService {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = Box<Future<Item=Response, Error=hyper::Error>>;
+
+    fn call(&self, request: Request) -> Self::Future {
+        let response = match (request.method(), request.path()) {
+            (&Method::Get, "/status") => {
+                json_response(status_json(&self.stats))
+            }
+            (&Method::Get, "/healthz") => self.health(),
+            (&Method::Post, "/play") => self.command(&request, ControlCommand::Play),
+            (&Method::Post, "/pause") => self.command(&request, ControlCommand::Pause),
+            (&Method::Post, "/next") => self.command(&request, ControlCommand::Next),
+            (&Method::Post, "/prev") => self.command(&request, ControlCommand::Prev),
+            (&Method::Post, "/volume") => {
+                match parse_volume(request.query()) {
+                    Some(volume) => self.command(&request, ControlCommand::Volume(volume)),
+                    None => Response::new().with_status(StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new().with_status(StatusCode::NotFound),
+        };
+
+        Box::new(future::ok(response))
+    }
+}
+
+/// Starts the control server, returning the stream of playback commands it receives
+pub fn serve(addr: SocketAddr, stats: Arc<Mutex<ScrobblerStats>>, secret: Option<String>,
+             handle: &Handle)
+    -> io::Result<mpsc::UnboundedReceiver<ControlCommand>>
+{
+    let (commands_tx, commands_rx) = mpsc::unbounded();
+
+    let service = ControlService {
+        stats: stats,
+        commands: commands_tx,
+        secret: secret,
+    };
+
+    let serve = try!(Http::new()
+        .serve_addr_handle(&addr, handle, move || Ok(service.clone()))
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err)));
+
+    info!("Control API listening on {}", addr);
+
+    let connection_handle = handle.clone();
+    let server = serve.for_each(move |connection| {
+        connection_handle.spawn(connection.map(|_| ()).map_err(|err| {
+            debug!("Control API connection error: {}", err);
+        }));
+        Ok(())
+    });
+
+    handle.spawn(server.map_err(|err| error!("Control API server error: {}", err)));
+    Ok(commands_rx)
+}
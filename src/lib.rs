@@ -12,17 +12,25 @@ extern crate serde_derive;
 extern crate serde_json;
 
 extern crate futures;
+extern crate hyper;
 extern crate num_bigint;
 extern crate protobuf;
 extern crate rand;
 extern crate rustfm_scrobble;
+#[cfg(feature = "scrobble-db")]
+extern crate rusqlite;
 extern crate tokio_core;
 
 pub extern crate librespot_core as core;
 pub extern crate librespot_protocol as protocol;
 pub extern crate librespot_metadata as metadata;
 
+pub mod control;
 pub mod keymaster;
+pub mod maloja;
+pub mod scrobble_db;
 pub mod scrobbler;
+pub mod skiplist;
+pub mod webhook;
 
 include!(concat!(env!("OUT_DIR"), "/lib.rs"));
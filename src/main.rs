@@ -5,30 +5,72 @@
 extern crate env_logger;
 extern crate futures;
 extern crate getopts;
+extern crate hyper;
 extern crate librespot;
+extern crate serde_json;
 extern crate tokio_core;
 extern crate tokio_signal;
 
 use env_logger::LogBuilder;
 use futures::{Future, Async, Poll, Stream};
+use futures::sync::mpsc;
+use hyper::Uri;
+use std::cmp;
+use std::collections::BTreeMap;
 use std::env;
+use std::fs::File;
 use std::io::{self, stderr, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::PathBuf;
 use std::process::exit;
 use std::str::FromStr;
-use tokio_core::reactor::{Handle, Core};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Handle, Core, Interval, Timeout};
 use tokio_core::io::IoStream;
 use std::mem;
 
-use librespot::core::authentication::{get_credentials, Credentials};
-use librespot::core::cache::Cache;
+use librespot::core::authentication::{get_credentials, read_credentials_file, Credentials, CredentialsRejected};
+use librespot::core::cache::{Cache, CachePaths};
 use librespot::core::config::{DeviceType, SessionConfig, ConnectConfig};
 use librespot::core::session::Session;
 use librespot::core::version;
+use librespot::metadata::ImageSize;
 
-use librespot::scrobbler::ScrobblerConfig;
+use librespot::control::{self, ControlCommand};
+use librespot::scrobble_db::ScrobbleDb;
+use librespot::skiplist::SkipList;
+use librespot::scrobbler::{self, DjContextPolicy, HeldScrobble, NowPlayingTrigger, ScrobbleError, ScrobblerConfig, ScrobblerStats};
 use librespot::spirc::{Spirc, SpircTask};
 
+// Process exit codes, so a wrapper or service manager can tell failures apart
+const EXIT_CONFIG: i32 = 1;
+const EXIT_AUTH: i32 = 2;
+const EXIT_NETWORK: i32 = 3;
+const EXIT_SPIRC: i32 = 4;
+const EXIT_SPOTIFY_AUTH: i32 = 5;
+
+/// How the informational modes (--print-cache-dir, --test-scrobbler, --scrobble-test) report
+#[derive(Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// Prints the outcome of a Last.fm check, as `Last.fm: OK, ...` or a JSON object
+fn print_check(format: OutputFormat, ok: bool, message: &str) {
+    match format {
+        OutputFormat::Text => println!("Last.fm: {}, {}", if ok { "OK" } else { "FAILED" }, message),
+        OutputFormat::Json => {
+            let mut result = BTreeMap::new();
+            result.insert("backend", serde_json::Value::String(String::from("lastfm")));
+            result.insert("ok", serde_json::Value::Bool(ok));
+            result.insert(if ok { "message" } else { "error" }, serde_json::Value::String(message.to_owned()));
+            println!("{}", serde_json::to_string(&result).unwrap());
+        }
+    }
+}
+
 fn usage(program: &str, opts: &getopts::Options) -> String {
     let brief = format!("Usage: {} [options]", program);
     opts.usage(&brief)
@@ -56,32 +98,168 @@ fn setup_logging(verbose: bool) {
     }
 }
 
+#[cfg(unix)]
+fn shutdown_signals(handle: &Handle) -> IoStream<()> {
+    use tokio_signal::unix::{Signal, SIGTERM};
+
+    // Service managers and container runtimes stop us with SIGTERM rather than Ctrl-C
+    let sigterm = Signal::new(SIGTERM, handle).flatten_stream().map(|_| ());
+    tokio_signal::ctrl_c(handle).flatten_stream().select(sigterm).boxed()
+}
+
+#[cfg(not(unix))]
+fn shutdown_signals(handle: &Handle) -> IoStream<()> {
+    tokio_signal::ctrl_c(handle).flatten_stream().boxed()
+}
+
+#[cfg(unix)]
+fn reload_signals(handle: &Handle) -> IoStream<()> {
+    use tokio_signal::unix::{Signal, SIGHUP};
+
+    Signal::new(SIGHUP, handle).flatten_stream().map(|_| ()).boxed()
+}
+
+#[cfg(not(unix))]
+fn reload_signals(_handle: &Handle) -> IoStream<()> {
+    futures::stream::empty().boxed()
+}
+
+#[cfg(unix)]
+fn dump_signals(handle: &Handle) -> IoStream<()> {
+    use tokio_signal::unix::{Signal, SIGUSR1};
+
+    Signal::new(SIGUSR1, handle).flatten_stream().map(|_| ()).boxed()
+}
+
+#[cfg(not(unix))]
+fn dump_signals(_handle: &Handle) -> IoStream<()> {
+    futures::stream::empty().boxed()
+}
+
 struct Setup {
     cache: Option<Cache>,
     session_config: SessionConfig,
     connect_config: ConnectConfig,
     credentials: Option<Credentials>,
-    scrobbler_config: ScrobblerConfig
+    scrobbler_config: Option<ScrobblerConfig>,
+    heartbeat_interval: Option<Duration>,
+    control_addr: Option<SocketAddr>,
+    control_secret: Option<String>,
+    stats_file: Option<PathBuf>,
+    max_reconnect_attempts: u32,
+}
+
+/// Logs the effective configuration for support requests; passwords and secrets are left out
+fn log_config(setup: &Setup, cache_location: Option<&str>) {
+    info!("Device: {} ({:?})", setup.connect_config.name, setup.connect_config.device_type);
+    info!("Cache: {}", cache_location.unwrap_or("disabled"));
+    info!("Country: {}", setup.session_config.country.as_ref().map_or("from account", String::as_str));
+    info!("Spotify credentials: {}", match setup.credentials {
+        Some(ref credentials) => credentials.username.as_str(),
+        None => "none",
+    });
+
+    match setup.scrobbler_config {
+        Some(ref config) => {
+            info!("Last.fm: enabled as {}", config.username);
+            info!("Scrobble options: album artist {}, cooldown {}s, min context tracks {}, now-playing on {:?}",
+                  config.use_album_artist, config.cooldown.as_secs(), config.min_context_tracks,
+                  config.now_playing_trigger);
+            info!("Timeouts: metadata {}, pause ends session {}",
+                  config.request_timeout.map_or(String::from("none"), |timeout| format!("{}s", timeout.as_secs())),
+                  config.pause_timeout.map_or(String::from("never"), |timeout| format!("after {}s", timeout.as_secs())));
+        }
+        None => info!("Last.fm: disabled"),
+    }
+
+    info!("Heartbeat: {}", setup.heartbeat_interval
+          .map_or(String::from("disabled"), |interval| format!("every {}s", interval.as_secs())));
+    info!("Control API: {}{}", setup.control_addr.map_or(String::from("disabled"), |addr| addr.to_string()),
+          if setup.control_secret.is_some() { " (secret required)" } else { "" });
+}
+
+/// Parses the value of `--<name>`, exiting with a configuration error if it doesn't parse
+fn parse_opt<T: FromStr>(matches: &getopts::Matches, name: &str) -> Option<T> {
+    matches.opt_str(name).map(|value| match T::from_str(&value) {
+        Ok(parsed) => parsed,
+        Err(_) => {
+            error!("Invalid value {:?} for --{}", value, name);
+            exit(EXIT_CONFIG);
+        }
+    })
+}
+
+fn required_opt(value: Option<String>, name: &str) -> String {
+    match value {
+        Some(value) => value,
+        None => {
+            error!("--{} is required", name);
+            exit(EXIT_CONFIG);
+        }
+    }
 }
 
 fn setup(args: &[String]) -> Setup {
     let mut opts = getopts::Options::new();
     opts.optopt("c", "cache", "Path to a directory where files will be cached.", "CACHE")
+        .optflag("", "read-only-cache", "Only read from the cache directory, never write to it")
         .optopt("n", "name", "Device name (defaults to Scrobbler)", "NAME")
         .optopt("", "device-type", "Displayed device type", "DEVICE_TYPE")
+        .optopt("", "country", "Two-letter country code to check track availability against (defaults to the account's)", "CODE")
+        .optopt("", "initial-volume", "Volume the device advertises, from 0 to 100 (defaults to 100)", "VOLUME")
         .optflag("v", "verbose", "Enable verbose output")
+        .optflag("", "trace-events", "Log every Spirc state frame fed to the scrobbler at debug level")
         .optopt("", "spotify-username", "Username to sign in with", "USERNAME")
         .optopt("", "spotify-password", "Password", "PASSWORD")
+        .optflag("", "prefer-cached-credentials", "Sign in with cached credentials when there are any, ignoring the username and password given")
+        .optopt("", "spotify-username-from-cache", "Sign in with the cached credentials of this account", "USERNAME")
+        .optopt("", "spotify-credentials-file", "File containing the Spotify username and password", "PATH")
         .optopt("", "lastfm-username", "Last.fm Username", "LASTFM_USERNAME")
         .optopt("", "lastfm-password", "Last.fm Password", "LASTFM_PASSWORD")
-        .optopt("", "lastfm-api-key", "Last.fm API Key", "API_KEY")
-        .optopt("", "lastfm-api-secret", "Last.fm API Secret", "SECRET");
+        .optopt("", "lastfm-api-key", "Last.fm API Key (defaults to the one built in, if any)", "API_KEY")
+        .optopt("", "lastfm-api-secret", "Last.fm API Secret (defaults to the one built in, if any)", "SECRET")
+        .optflag("", "use-album-artist", "Scrobble under the album artist rather than the track artist")
+        .optflag("", "scrobble-threshold-debug", "Log why each track was or wasn't scrobbled")
+        .optopt("", "http-timeout", "Timeout for metadata requests in seconds (defaults to 10, 0 disables)", "SECONDS")
+        .optopt("", "scrobble-cooldown", "Don't scrobble a track again within SECONDS of its last scrobble (defaults to 0)", "SECONDS")
+        .optopt("", "scrobble-min-album-tracks", "Don't scrobble tracks played from a context with fewer than N tracks", "N")
+        .optopt("", "scrobble-paused-as-stopped", "Treat a pause longer than SECONDS as the end of the listening session", "SECONDS")
+        .optflagopt("", "scrobble-artist-split", "Scrobble only the artist before the first SEPARATORS match (|-separated, defaults to \" feat. | ft. |, | & \")", "SEPARATORS")
+        .optopt("", "scrobble-webhook", "POST a JSON event to this http:// URL for every scrobble", "URL")
+        .optopt("", "scrobble-db", "Record scrobbles in this SQLite database (needs the scrobble-db feature)", "PATH")
+        .optflag("", "scrobble-db-plays", "Also record each track as it starts playing in the scrobble database")
+        .optopt("", "maloja-url", "Also scrobble to the Maloja server at this http:// URL", "URL")
+        .optopt("", "maloja-key", "API key for the Maloja server", "KEY")
+        .optflag("", "scrobble-webhook-now-playing", "Also POST now-playing events to the scrobble webhook")
+        .optopt("", "cover-art-size", "Cover image size for the webhook and control API: small, default, large or xlarge (defaults to large)", "SIZE")
+        .optopt("", "scrobble-inhibit-command", "Run PROGRAM before each scrobble and skip it if PROGRAM exits non-zero", "PROGRAM")
+        .optopt("", "scrobble-filter-command", "Pass each track's metadata as JSON to PROGRAM and skip the scrobble if it exits non-zero", "PROGRAM")
+        .optopt("", "play-time-offset", "Don't count the first SECONDS of each play towards the scrobble threshold (defaults to 0)", "SECONDS")
+        .optopt("", "scrobble-skiplist", "Never scrobble the tracks listed in this file (reloaded on SIGHUP)", "PATH")
+        .optopt("", "session-gap-minutes", "Start a new listening session after this long without playback (defaults to 30)", "MINUTES")
+        .optopt("", "scrobble-delay", "Wait SECONDS after a track qualifies before scrobbling it (defaults to 0)", "SECONDS")
+        .optopt("", "dj-context-policy", "Tracks played by the Spotify DJ or radio: scrobble or skip (defaults to scrobble)", "POLICY")
+        .optflag("", "scrobble-on-exit-only", "Hold scrobbles in memory and submit them all on clean shutdown")
+        .optopt("", "scrobble-when-muted", "Count play time while the playing device's volume is 0: true or false (defaults to true)", "BOOL")
+        .optopt("", "now-playing-min-interval", "Don't repeat now-playing for the same track within SECONDS (defaults to 5)", "SECONDS")
+        .optopt("", "now-playing-trigger", "When to send now-playing: select or playing (defaults to playing)", "TRIGGER")
+        .optopt("", "max-track-gap", "Warn when a track's position and the wall clock drift more than SECONDS apart", "SECONDS")
+        .optopt("", "heartbeat-interval", "Log a liveness summary every SECONDS (defaults to 0, disabled)", "SECONDS")
+        .optflag("", "require-scrobbler", "Exit if Last.fm authentication fails instead of running without scrobbling")
+        .optflag("", "print-cache-dir", "Print where cached data is kept and exit")
+        .optopt("", "output-format", "Output of the informational modes: text or json (defaults to text)", "FORMAT")
+        .optopt("", "scrobble-test", "Scrobble \"ARTIST - TITLE\" once, report the result and exit", "TRACK")
+        .optflag("", "test-scrobbler", "Check the Last.fm credentials, report the result and exit")
+        .optopt("", "max-reconnect-attempts", "Exit after this many failed attempts to reconnect to Spotify (defaults to 0, retry forever)", "N")
+        .optopt("", "control-addr", "Serve a local JSON status and playback control API on this address", "HOST:PORT")
+        .optopt("", "control-secret", "Require this value in the X-Control-Secret header for control requests", "SECRET")
+        .optopt("", "stats-file", "Write the status JSON here on SIGUSR1 instead of to stdout", "PATH");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => {
             writeln!(stderr(), "error: {}\n{}", f.to_string(), usage(&args[0], &opts)).unwrap();
-            exit(1);
+            exit(EXIT_CONFIG);
         }
     };
 
@@ -94,17 +272,115 @@ fn setup(args: &[String]) -> Setup {
              version::short_now(),
              version::build_id());
 
+    let output_format = match matches.opt_str("output-format").as_ref().map(String::as_str) {
+        Some("text") | None => OutputFormat::Text,
+        Some("json") => OutputFormat::Json,
+        Some(format) => {
+            error!("Invalid output format {:?}, expected text or json", format);
+            exit(EXIT_CONFIG);
+        }
+    };
+
+    if matches.opt_present("print-cache-dir") {
+        match matches.opt_str("c") {
+            Some(cache_location) => {
+                let paths = CachePaths::new(&PathBuf::from(cache_location));
+                match output_format {
+                    OutputFormat::Text => {
+                        println!("root: {}", paths.root.display());
+                        println!("credentials: {}", paths.credentials.display());
+                        println!("accounts: {}", paths.accounts.display());
+                        println!("files: {}", paths.files.display());
+                        println!("scrobbler_state: {}", paths.scrobbler_state.display());
+                    }
+                    OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&paths).unwrap()),
+                }
+                exit(0);
+            }
+            None => {
+                writeln!(stderr(), "error: no cache directory is configured, pass --cache").unwrap();
+                exit(EXIT_CONFIG);
+            }
+        }
+    }
+
     let name = matches.opt_str("name").unwrap_or(String::from("Scrobbler"));
     let use_audio_cache = false;
 
     let cache = matches.opt_str("c").map(|cache_location| {
-        Cache::new(PathBuf::from(cache_location), use_audio_cache)
+        let cache = if matches.opt_present("read-only-cache") {
+            Cache::read_only(PathBuf::from(&cache_location))
+        } else {
+            Cache::new(PathBuf::from(&cache_location), use_audio_cache)
+        };
+
+        match cache {
+            Ok(cache) => cache,
+            Err(err) => {
+                error!("Cache directory {} is not usable: {}", cache_location, err);
+                exit(EXIT_CONFIG);
+            }
+        }
     });
 
-    let cached_credentials = cache.as_ref().and_then(Cache::credentials);
-    let credentials = get_credentials(matches.opt_str("spotify-username"),
-                                      matches.opt_str("spotify-password"),
-                                      cached_credentials);
+    let mut spotify_username = matches.opt_str("spotify-username");
+    let mut spotify_password = matches.opt_str("spotify-password");
+
+    // Flags win over the file, and the file's password is only used for the file's username
+    if let Some(path) = matches.opt_str("spotify-credentials-file") {
+        let (file_username, file_password) = match read_credentials_file(&path) {
+            Ok(credentials) => credentials,
+            Err(err) => {
+                error!("Could not read Spotify credentials file {}: {}", path, err);
+                exit(EXIT_CONFIG);
+            }
+        };
+
+        if spotify_username.is_none() || spotify_username.as_ref() == Some(&file_username) {
+            spotify_username = Some(file_username);
+            spotify_password = spotify_password.or(Some(file_password));
+        }
+    }
+
+    let cached_credentials = match matches.opt_str("spotify-username-from-cache") {
+        Some(username) => {
+            let cache = match cache.as_ref() {
+                Some(cache) => cache,
+                None => {
+                    error!("--spotify-username-from-cache needs --cache");
+                    exit(EXIT_CONFIG);
+                }
+            };
+            match cache.account_credentials(&username) {
+                Some(credentials) => Some(credentials),
+                None => {
+                    error!("No cached credentials for {}, cached accounts: {}",
+                           username, cache.cached_usernames().join(", "));
+                    exit(EXIT_CONFIG);
+                }
+            }
+        }
+        None => cache.as_ref().and_then(Cache::credentials),
+    };
+    // Templated units may pass placeholder credentials that should only count before first sign-in
+    let credentials = match cached_credentials {
+        Some(credentials) if matches.opt_present("prefer-cached-credentials") => {
+            info!("Using cached credentials for {}", credentials.username);
+            Some(credentials)
+        }
+        cached_credentials => get_credentials(spotify_username,
+                                              spotify_password,
+                                              cached_credentials),
+    };
+
+    let country = matches.opt_str("country").map(|country| {
+        let country = country.to_uppercase();
+        if country.len() != 2 || !country.chars().all(|c| c >= 'A' && c <= 'Z') {
+            error!("Invalid country {:?}, expected a two-letter code such as GB", country);
+            exit(EXIT_CONFIG);
+        }
+        country
+    });
 
     let session_config = {
         let device_id = librespot::core::session::device_id(&name);
@@ -112,39 +388,252 @@ fn setup(args: &[String]) -> Setup {
         SessionConfig {
             user_agent: version::version_string(),
             device_id: device_id,
+            country: country,
         }
     };
 
-    let api_key = matches.opt_str("lastfm-api-key").expect("Invalid Last.fm API key");
-    let api_secret = matches.opt_str("lastfm-api-secret").expect("Invalid Last.fm API secret");
-    let username = matches.opt_str("lastfm-username").expect("Invalid Last.fm username");
-    let password = matches.opt_str("lastfm-password").expect("Invalid Last.fm password");
+    // Builds can bake in an application key so users don't have to register their own
+    let api_key = required_opt(matches.opt_str("lastfm-api-key")
+        .or(option_env!("LASTFM_API_KEY").map(String::from)), "lastfm-api-key");
+    let api_secret = required_opt(matches.opt_str("lastfm-api-secret")
+        .or(option_env!("LASTFM_API_SECRET").map(String::from)), "lastfm-api-secret");
+    let username = required_opt(matches.opt_str("lastfm-username"), "lastfm-username");
+    let password = required_opt(matches.opt_str("lastfm-password"), "lastfm-password");
+
+    let request_timeout = parse_opt::<u64>(&matches, "http-timeout").unwrap_or(10);
+
+    let cooldown = parse_opt::<u64>(&matches, "scrobble-cooldown").unwrap_or(0);
+
+    let min_context_tracks = parse_opt::<usize>(&matches, "scrobble-min-album-tracks").unwrap_or(0);
+
+    let now_playing_trigger = parse_opt::<NowPlayingTrigger>(&matches, "now-playing-trigger")
+        .unwrap_or(NowPlayingTrigger::Playing);
+
+    let now_playing_interval = parse_opt::<u64>(&matches, "now-playing-min-interval").unwrap_or(5);
+
+    let pause_timeout = parse_opt::<u64>(&matches, "scrobble-paused-as-stopped");
+
+    let artist_separators = matches.opt_default("scrobble-artist-split", " feat. | ft. |, | & ")
+        .map(|separators| {
+            separators.split('|').filter(|separator| !separator.is_empty()).map(String::from).collect()
+        })
+        .unwrap_or(Vec::new());
+
+    let webhook = parse_opt::<Uri>(&matches, "scrobble-webhook");
+
+    let maloja_url = matches.opt_str("maloja-url").map(|url| {
+        let endpoint = format!("{}/apis/mlj_1/newscrobble", url.trim_right_matches('/'));
+        match Uri::from_str(&endpoint) {
+            Ok(uri) => uri,
+            Err(_) => {
+                error!("Invalid Maloja URL {:?}", url);
+                exit(EXIT_CONFIG);
+            }
+        }
+    });
+    let maloja_key = matches.opt_str("maloja-key").unwrap_or_default();
+    if maloja_url.is_some() && maloja_key.is_empty() {
+        error!("--maloja-url needs an API key given with --maloja-key");
+        exit(EXIT_CONFIG);
+    }
+
+    let cover_size = match matches.opt_str("cover-art-size").as_ref().map(String::as_str) {
+        Some("small") => ImageSize::SMALL,
+        Some("default") => ImageSize::DEFAULT,
+        Some("large") | None => ImageSize::LARGE,
+        Some("xlarge") => ImageSize::XLARGE,
+        Some(size) => {
+            error!("Invalid cover art size {:?}, expected small, default, large or xlarge", size);
+            exit(EXIT_CONFIG);
+        }
+    };
+
+    let play_time_offset = parse_opt::<u64>(&matches, "play-time-offset").unwrap_or(0);
+
+    let skiplist = matches.opt_str("scrobble-skiplist").map(|path| {
+        match SkipList::load(&path) {
+            Ok(skiplist) => Arc::new(Mutex::new(skiplist)),
+            Err(err) => {
+                error!("Could not read skip-list {}: {}", path, err);
+                exit(EXIT_CONFIG);
+            }
+        }
+    });
+
+    let session_gap = parse_opt::<u64>(&matches, "session-gap-minutes").unwrap_or(30);
+
+    let dj_context_policy = parse_opt::<DjContextPolicy>(&matches, "dj-context-policy")
+        .unwrap_or(DjContextPolicy::Scrobble);
+
+    let scrobble_delay = parse_opt::<u64>(&matches, "scrobble-delay").unwrap_or(0);
+
+    let scrobble_when_muted = parse_opt::<bool>(&matches, "scrobble-when-muted").unwrap_or(true);
+
+    // Created and migrated up front, so a bad path is reported before connecting
+    let scrobble_db = matches.opt_str("scrobble-db").map(PathBuf::from);
+    if let Some(ref path) = scrobble_db {
+        if let Err(err) = ScrobbleDb::open(path) {
+            error!("Could not open scrobble database {}: {}", path.display(), err);
+            exit(EXIT_CONFIG);
+        }
+    }
+
+    let max_track_gap = parse_opt::<u64>(&matches, "max-track-gap");
 
     let scrobbler_config = ScrobblerConfig {
         api_key: api_key,
         api_secret: api_secret,
         username: username,
         password: password,
+        session_key: None,
+        use_album_artist: matches.opt_present("use-album-artist"),
+        threshold_debug: matches.opt_present("scrobble-threshold-debug"),
+        request_timeout: if request_timeout > 0 { Some(Duration::from_secs(request_timeout)) } else { None },
+        cooldown: Duration::from_secs(cooldown),
+        min_context_tracks: min_context_tracks,
+        now_playing_trigger: now_playing_trigger,
+        pause_timeout: pause_timeout.map(Duration::from_secs),
+        artist_separators: artist_separators,
+        webhook: webhook,
+        webhook_now_playing: matches.opt_present("scrobble-webhook-now-playing"),
+        cover_size: cover_size,
+        inhibit_command: matches.opt_str("scrobble-inhibit-command"),
+        filter_command: matches.opt_str("scrobble-filter-command"),
+        play_time_offset: Duration::from_secs(play_time_offset),
+        skiplist: skiplist,
+        session_gap: Duration::from_secs(session_gap * 60),
+        scrobble_when_muted: scrobble_when_muted,
+        scrobble_delay: Duration::from_secs(scrobble_delay),
+        scrobble_on_exit_only: matches.opt_present("scrobble-on-exit-only"),
+        dj_context_policy: dj_context_policy,
+        maloja_url: maloja_url,
+        maloja_key: maloja_key,
+        now_playing_interval: Duration::from_secs(now_playing_interval),
+        max_track_gap: max_track_gap.map(Duration::from_secs),
+        scrobble_db: scrobble_db,
+        scrobble_db_plays: matches.opt_present("scrobble-db-plays"),
     };
 
+    if matches.opt_present("test-scrobbler") {
+        match scrobbler::authenticate(&scrobbler_config) {
+            Ok(_) => {
+                print_check(output_format, true, &format!("authenticated as {}", scrobbler_config.username));
+                exit(0);
+            }
+            Err(err @ ScrobbleError::Auth(_)) => {
+                print_check(output_format, false, &err.to_string());
+                exit(EXIT_AUTH);
+            }
+            Err(err) => {
+                print_check(output_format, false, &err.to_string());
+                exit(EXIT_NETWORK);
+            }
+        }
+    }
+
+    if let Some(track) = matches.opt_str("scrobble-test") {
+        let (artist, title) = match track.find(" - ") {
+            Some(index) => (&track[..index], &track[index + 3..]),
+            None => {
+                writeln!(stderr(), "error: --scrobble-test expects \"ARTIST - TITLE\"").unwrap();
+                exit(EXIT_CONFIG);
+            }
+        };
+
+        let result = scrobbler::authenticate(&scrobbler_config).and_then(|session_key| {
+            let config = ScrobblerConfig { session_key: Some(session_key), ..scrobbler_config.clone() };
+            scrobbler::submit_test_scrobble(&config, artist, title)
+        });
+        match result {
+            Ok(()) => {
+                let message = format!("scrobbled {} - {} as {}", artist, title, scrobbler_config.username);
+                print_check(output_format, true, &message);
+                exit(0);
+            }
+            Err(err @ ScrobbleError::Auth(_)) => {
+                print_check(output_format, false, &err.to_string());
+                exit(EXIT_AUTH);
+            }
+            Err(err) => {
+                print_check(output_format, false, &err.to_string());
+                exit(EXIT_NETWORK);
+            }
+        }
+    }
+
+    let scrobbler_config = match scrobbler::authenticate(&scrobbler_config) {
+        Ok(session_key) => {
+            info!("Authenticated with Last.fm as {}", scrobbler_config.username);
+            Some(ScrobblerConfig { session_key: Some(session_key), ..scrobbler_config })
+        }
+        Err(err @ ScrobbleError::Auth(_)) => {
+            error!("{}", err);
+            if matches.opt_present("require-scrobbler") {
+                exit(EXIT_AUTH);
+            }
+            warn!("Continuing with scrobbling disabled");
+            None
+        }
+        Err(err) => {
+            // Booting before the network is up shouldn't cost a whole run of scrobbles
+            warn!("{}; logging in again once connected to Spotify", err);
+            Some(scrobbler_config)
+        }
+    };
+
+    let heartbeat_interval = parse_opt::<u64>(&matches, "heartbeat-interval").unwrap_or(0);
+
+    let max_reconnect_attempts = parse_opt::<u32>(&matches, "max-reconnect-attempts").unwrap_or(0);
+
+    let control_addr = matches.opt_str("control-addr").map(|addr| {
+        match addr.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) {
+            Some(addr) => addr,
+            None => {
+                error!("Invalid control address {:?}", addr);
+                exit(EXIT_CONFIG);
+            }
+        }
+    });
+
     let connect_config = {
-        let device_type = matches.opt_str("device-type").as_ref()
-            .map(|device_type| DeviceType::from_str(device_type).expect("Invalid device type"))
-            .unwrap_or(DeviceType::default());
+        let device_type = parse_opt::<DeviceType>(&matches, "device-type").unwrap_or(DeviceType::default());
+
+        let initial_volume = matches.opt_str("initial-volume")
+            .map(|volume| match u32::from_str(&volume) {
+                Ok(percent) if percent <= 100 => percent,
+                _ => {
+                    error!("Invalid initial volume {:?}, expected a percentage from 0 to 100", volume);
+                    exit(EXIT_CONFIG);
+                }
+            })
+            .unwrap_or(100);
 
         ConnectConfig {
             name: name,
             device_type: device_type,
+            trace_events: matches.opt_present("trace-events"),
+            initial_volume: (initial_volume * 0xFFFF / 100) as u16,
         }
     };
 
-    Setup {
+    let setup = Setup {
         cache: cache,
         session_config: session_config,
         connect_config: connect_config,
         credentials: credentials,
-        scrobbler_config: scrobbler_config
+        scrobbler_config: scrobbler_config,
+        heartbeat_interval: if heartbeat_interval > 0 { Some(Duration::from_secs(heartbeat_interval)) } else { None },
+        control_addr: control_addr,
+        control_secret: matches.opt_str("control-secret"),
+        stats_file: matches.opt_str("stats-file").map(PathBuf::from),
+        max_reconnect_attempts: max_reconnect_attempts,
+    };
+
+    if verbose {
+        log_config(&setup, matches.opt_str("c").as_ref().map(String::as_str));
     }
+
+    setup
 }
 
 struct Main {
@@ -154,12 +643,23 @@ struct Main {
     handle: Handle,
 
     signal: IoStream<()>,
+    reload: IoStream<()>,
+    dump: IoStream<()>,
+    stats_file: Option<PathBuf>,
+    heartbeat: Option<Interval>,
+    control: Option<mpsc::UnboundedReceiver<ControlCommand>>,
 
     spirc: Option<Spirc>,
     spirc_task: Option<SpircTask>,
     connect: Box<Future<Item=Session, Error=io::Error>>,
+    last_credentials: Option<Credentials>,
+    reconnect: Option<Timeout>,
+    reconnect_attempts: u32,
+    max_reconnect_attempts: u32,
 
-    scrobbler_config: ScrobblerConfig,
+    scrobbler_config: Option<ScrobblerConfig>,
+    scrobbler_stats: Arc<Mutex<ScrobblerStats>>,
+    held_scrobbles: Vec<HeldScrobble>,
 
     shutdown: bool,
 }
@@ -173,12 +673,38 @@ impl Main {
             connect_config: setup.connect_config,
 
             connect: Box::new(futures::future::empty()),
+            last_credentials: None,
+            reconnect: None,
+            reconnect_attempts: 0,
+            max_reconnect_attempts: setup.max_reconnect_attempts,
             spirc: None,
             spirc_task: None,
             shutdown: false,
-            signal: tokio_signal::ctrl_c(&handle).flatten_stream().boxed(),
-            scrobbler_config: setup.scrobbler_config
+            signal: shutdown_signals(&handle),
+            reload: reload_signals(&handle),
+            dump: dump_signals(&handle),
+            stats_file: setup.stats_file,
+            heartbeat: setup.heartbeat_interval.map(|interval| Interval::new(interval, &handle).unwrap()),
+            control: None,
+            scrobbler_config: setup.scrobbler_config,
+            scrobbler_stats: Arc::new(Mutex::new(ScrobblerStats::default())),
+            held_scrobbles: Vec::new(),
         };
+        {
+            let mut stats = task.scrobbler_stats.lock().unwrap();
+            stats.started_at = Some(Instant::now());
+            stats.lastfm_failed = task.scrobbler_config.is_none();
+        }
+
+        if let Some(addr) = setup.control_addr {
+            match control::serve(addr, task.scrobbler_stats.clone(), setup.control_secret, &handle) {
+                Ok(commands) => task.control = Some(commands),
+                Err(err) => {
+                    error!("Could not start control API on {}: {}", addr, err);
+                    exit(EXIT_CONFIG);
+                }
+            }
+        }
 
         if let Some(credentials) = setup.credentials {
             task.credentials(credentials);
@@ -187,19 +713,83 @@ impl Main {
         task
     }
 
+    fn log_heartbeat(&self) {
+        let stats = self.scrobbler_stats.lock().unwrap();
+
+        let current_track = stats.current_track
+            .map(|track_id| track_id.to_base16())
+            .unwrap_or(String::from("none"));
+        let last_scrobble = stats.last_scrobble
+            .map(|last| format!("{} min ago", last.elapsed().as_secs() / 60))
+            .unwrap_or(String::from("never"));
+
+        info!("Heartbeat: connected={}, active={}, current track={}, scrobbles={}, last scrobble {}",
+              if self.spirc.is_some() { "yes" } else { "no" },
+              if stats.active { "yes" } else { "no" },
+              current_track, stats.scrobbles, last_scrobble);
+    }
+
+    fn dump_stats(&self) {
+        let status = control::status_json(&self.scrobbler_stats);
+        match self.stats_file {
+            Some(ref path) => {
+                let result = File::create(path).and_then(|mut file| writeln!(file, "{}", status));
+                if let Err(err) = result {
+                    error!("Could not write stats to {}: {}", path.display(), err);
+                }
+            }
+            None => println!("{}", status),
+        }
+    }
+
     fn credentials(&mut self, credentials: Credentials) {
         let config = self.session_config.clone();
         let handle = self.handle.clone();
 
+        self.last_credentials = Some(credentials.clone());
         let connection = Session::connect(config, credentials, self.cache.clone(), handle);
 
         self.connect = connection;
         self.spirc = None;
+        self.scrobbler_stats.lock().unwrap().connected = false;
         let task = mem::replace(&mut self.spirc_task, None);
         if let Some(task) = task {
             self.handle.spawn(task);
         }
     }
+
+    fn reload_skiplist(&self) {
+        let skiplist = match self.scrobbler_config.as_ref().and_then(|config| config.skiplist.as_ref()) {
+            Some(skiplist) => skiplist,
+            None => return,
+        };
+
+        let mut skiplist = skiplist.lock().unwrap();
+        if let Err(err) = skiplist.reload() {
+            error!("Could not reload skip-list {}, keeping the old entries: {}", skiplist.path().display(), err);
+        }
+    }
+
+    /// `exit_code` is used if this failure exhausts the reconnect attempts
+    fn schedule_reconnect(&mut self, exit_code: i32) {
+        self.reconnect_attempts += 1;
+        if self.max_reconnect_attempts > 0 && self.reconnect_attempts > self.max_reconnect_attempts {
+            error!("Giving up after {} failed attempts to reconnect to Spotify", self.max_reconnect_attempts);
+            exit(exit_code);
+        }
+
+        // Back off exponentially, so a Spotify outage isn't met with a reconnect storm
+        let delay = cmp::min(1 << cmp::min(self.reconnect_attempts, 6), 60);
+        warn!("Reconnecting to Spotify in {}s (attempt {})", delay, self.reconnect_attempts);
+
+        // Scrobbles held for shutdown or a clock fix move over to the next connection's scrobbler
+        if let Some(mut spirc_task) = self.spirc_task.take() {
+            self.held_scrobbles = spirc_task.take_held_scrobbles();
+        }
+        self.spirc = None;
+        self.scrobbler_stats.lock().unwrap().connected = false;
+        self.reconnect = Some(Timeout::new(Duration::from_secs(delay), &self.handle).unwrap());
+    }
 }
 
 impl Future for Main {
@@ -210,38 +800,123 @@ impl Future for Main {
         loop {
             let mut progress = false;
 
-            if let Async::Ready(session) = self.connect.poll().unwrap() {
-                self.connect = Box::new(futures::future::empty());
-                let connect_config = self.connect_config.clone();
+            // Once shutting down, a connection that completes now would only start a fresh Spirc
+            let connect = if self.shutdown { Ok(Async::NotReady) } else { self.connect.poll() };
+            match connect {
+                Ok(Async::Ready(session)) => {
+                    self.connect = Box::new(futures::future::empty());
+                    self.reconnect_attempts = 0;
+                    let connect_config = self.connect_config.clone();
+
+                    let (spirc, mut spirc_task) = Spirc::new(connect_config, session, self.scrobbler_config.clone(),
+                                                           self.scrobbler_stats.clone(), self.handle.clone());
+                    spirc_task.restore_held_scrobbles(mem::replace(&mut self.held_scrobbles, Vec::new()));
+                    self.spirc = Some(spirc);
+                    self.spirc_task = Some(spirc_task);
+                    {
+                        let mut stats = self.scrobbler_stats.lock().unwrap();
+                        stats.connected = true;
+                        // The new Scrobbler starts out with Last.fm enabled again
+                        stats.lastfm_failed = self.scrobbler_config.is_none();
+                    }
 
-                let (spirc, spirc_task) = Spirc::new(connect_config, session, self.scrobbler_config.clone());
-                self.spirc = Some(spirc);
-                self.spirc_task = Some(spirc_task);
+                    progress = true;
+                }
+                Ok(Async::NotReady) => (),
+                Err(ref err) if CredentialsRejected::is(err) => {
+                    error!("Spotify rejected the credentials ({}), not retrying. Sign in again with \
+                            --spotify-username and --spotify-password", err);
+                    exit(EXIT_SPOTIFY_AUTH);
+                }
+                Err(err) => {
+                    error!("Could not connect to Spotify: {}", err);
+                    self.connect = Box::new(futures::future::empty());
+                    self.schedule_reconnect(EXIT_NETWORK);
+                    progress = true;
+                }
+            }
 
+            let reconnect_due = match self.reconnect {
+                Some(ref mut reconnect) if !self.shutdown => reconnect.poll().unwrap().is_ready(),
+                _ => false,
+            };
+            if reconnect_due {
+                self.reconnect = None;
+                if let Some(credentials) = self.last_credentials.clone() {
+                    self.credentials(credentials);
+                }
                 progress = true;
             }
 
             if let Async::Ready(Some(())) = self.signal.poll().unwrap() {
-                if !self.shutdown {
-                    if let Some(ref spirc) = self.spirc {
-                        spirc.shutdown();
-                    }
-                    self.shutdown = true;
-                } else {
+                if self.shutdown {
                     return Ok(Async::Ready(()));
                 }
 
+                match self.spirc {
+                    Some(ref spirc) => spirc.shutdown(),
+                    // Between connections there's no Spirc whose shutdown we could wait for
+                    None => {
+                        if !self.held_scrobbles.is_empty() {
+                            warn!("Stopped while reconnecting to Spotify, {} held scrobbles are lost",
+                                  self.held_scrobbles.len());
+                        }
+                        return Ok(Async::Ready(()));
+                    }
+                }
+                self.shutdown = true;
+
                 progress = true;
             }
 
-            if let Some(ref mut spirc_task) = self.spirc_task {
-                if let Async::Ready(()) = spirc_task.poll().unwrap() {
-                    if self.shutdown {
-                        return Ok(Async::Ready(()));
-                    } else {
-                        panic!("Spirc shut down unexpectedly");
+            if let Async::Ready(Some(())) = self.reload.poll().unwrap() {
+                self.reload_skiplist();
+                progress = true;
+            }
+
+            if let Async::Ready(Some(())) = self.dump.poll().unwrap() {
+                self.dump_stats();
+                progress = true;
+            }
+
+            let command = match self.control {
+                Some(ref mut control) => control.poll().unwrap(),
+                None => Async::NotReady,
+            };
+            if let Async::Ready(Some(command)) = command {
+                if let Some(ref spirc) = self.spirc {
+                    match command {
+                        ControlCommand::Play => spirc.play(),
+                        ControlCommand::Pause => spirc.pause(),
+                        ControlCommand::Next => spirc.next(),
+                        ControlCommand::Prev => spirc.prev(),
+                        ControlCommand::Volume(volume) => spirc.volume(volume),
                     }
                 }
+                progress = true;
+            }
+
+            let heartbeat = match self.heartbeat {
+                Some(ref mut heartbeat) => heartbeat.poll().unwrap(),
+                None => Async::NotReady,
+            };
+            if let Async::Ready(Some(())) = heartbeat {
+                self.log_heartbeat();
+                progress = true;
+            }
+
+            let spirc_done = match self.spirc_task {
+                Some(ref mut spirc_task) => spirc_task.poll().unwrap().is_ready(),
+                None => false,
+            };
+            if spirc_done {
+                if self.shutdown {
+                    return Ok(Async::Ready(()));
+                }
+
+                error!("Spirc shut down unexpectedly");
+                self.schedule_reconnect(EXIT_SPIRC);
+                progress = true;
             }
 
             if !progress {
@@ -0,0 +1,75 @@
+use futures::Future;
+use hyper::{Client, Method, Request, Uri};
+use hyper::client::HttpConnector;
+use hyper::header::{ContentLength, ContentType};
+use serde_json;
+use tokio_core::reactor::Handle;
+
+use core::util::now_ms;
+use scrobbler::TrackMeta;
+
+#[derive(Serialize, Debug)]
+struct NewScrobble<'a> {
+    artists: Vec<&'a str>,
+    title: &'a str,
+    album: Option<&'a str>,
+    duration: u64,
+    time: u64,
+    key: &'a str,
+}
+
+/// Submits scrobbles to a self-hosted Maloja server through its native API, alongside Last.fm.
+/// Like the webhook this is best-effort: a failed submission is logged and not retried.
+pub struct Maloja {
+    client: Client<HttpConnector>,
+    uri: Uri,
+    key: String,
+    handle: Handle,
+}
+
+impl Maloja {
+    /// `uri` is the server's `/apis/mlj_1/newscrobble` endpoint
+    pub fn new(uri: Uri, key: String, handle: &Handle) -> Maloja {
+        Maloja {
+            client: Client::new(handle),
+            uri: uri,
+            key: key,
+            handle: handle.clone(),
+        }
+    }
+
+    pub fn scrobble(&self, meta: &TrackMeta) {
+        let body = serde_json::to_string(&NewScrobble {
+            artists: vec![meta.artist.as_str()],
+            title: &meta.title,
+            album: if meta.album.is_empty() { None } else { Some(meta.album.as_str()) },
+            duration: meta.duration.as_secs(),
+            time: now_ms() as u64 / 1000,
+            key: &self.key,
+        }).unwrap();
+
+        let mut request = Request::new(Method::Post, self.uri.clone());
+        request.headers_mut().set(ContentType::json());
+        request.headers_mut().set(ContentLength(body.len() as u64));
+        request.set_body(body);
+
+        let uri = self.uri.clone();
+        let title = meta.title.clone();
+        let submission = self.client.request(request).then(move |result| {
+            match result {
+                Ok(ref response) if response.status().is_success() => {
+                    debug!("Scrobbled {:?} to Maloja", title);
+                }
+                Ok(response) => {
+                    warn!("Maloja {} rejected scrobble of {:?}: {}", uri, title, response.status());
+                }
+                Err(err) => {
+                    warn!("Could not scrobble {:?} to Maloja {}: {}", title, uri, err);
+                }
+            }
+            Ok(())
+        });
+
+        self.handle.spawn(submission);
+    }
+}
@@ -0,0 +1,75 @@
+use std::path::Path;
+
+use scrobbler::TrackMeta;
+
+/// Bumped whenever the schema changes, with a migration step added to `migrate`
+#[cfg(feature = "scrobble-db")]
+const SCHEMA_VERSION: i32 = 1;
+
+/// Local scrobble history in a SQLite database, for querying later. Only available when built
+/// with the `scrobble-db` feature, as it needs the SQLite library.
+#[cfg(feature = "scrobble-db")]
+pub struct ScrobbleDb {
+    conn: ::rusqlite::Connection,
+}
+
+#[cfg(feature = "scrobble-db")]
+impl ScrobbleDb {
+    pub fn open(path: &Path) -> Result<ScrobbleDb, String> {
+        let conn = try!(::rusqlite::Connection::open(path).map_err(|err| err.to_string()));
+        let db = ScrobbleDb { conn: conn };
+        try!(db.migrate().map_err(|err| err.to_string()));
+        Ok(db)
+    }
+
+    fn migrate(&self) -> ::rusqlite::Result<()> {
+        let version: i32 = try!(self.conn.query_row("PRAGMA user_version", &[], |row| row.get(0)));
+
+        if version < 1 {
+            try!(self.conn.execute_batch("
+                CREATE TABLE scrobbles (
+                    id INTEGER PRIMARY KEY,
+                    timestamp INTEGER NOT NULL,
+                    artist TEXT NOT NULL,
+                    title TEXT NOT NULL,
+                    album TEXT,
+                    uri TEXT NOT NULL,
+                    backend TEXT NOT NULL,
+                    status TEXT NOT NULL
+                );
+                CREATE INDEX scrobbles_timestamp ON scrobbles (timestamp);"));
+        }
+
+        if version < SCHEMA_VERSION {
+            try!(self.conn.execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION)));
+        }
+        Ok(())
+    }
+
+    /// `status` is `scrobbled`, `failed` or `playing`
+    pub fn record(&self, timestamp: i64, artist: &str, meta: &TrackMeta, backend: &str, status: &str) {
+        let album = if meta.album.is_empty() { None } else { Some(meta.album.clone()) };
+        let result = self.conn.execute(
+            "INSERT INTO scrobbles (timestamp, artist, title, album, uri, backend, status)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            &[&timestamp, &artist, &meta.title, &album, &meta.uri, &backend, &status]);
+
+        if let Err(err) = result {
+            warn!("Could not record {} play of {:?} in the scrobble database: {}", status, meta.title, err);
+        }
+    }
+}
+
+/// Stand-in for builds without the `scrobble-db` feature, which can't be opened
+#[cfg(not(feature = "scrobble-db"))]
+pub struct ScrobbleDb;
+
+#[cfg(not(feature = "scrobble-db"))]
+impl ScrobbleDb {
+    pub fn open(_path: &Path) -> Result<ScrobbleDb, String> {
+        Err(String::from("built without the scrobble-db feature"))
+    }
+
+    pub fn record(&self, _timestamp: i64, _artist: &str, _meta: &TrackMeta, _backend: &str, _status: &str) {
+    }
+}
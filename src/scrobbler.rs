@@ -1,12 +1,32 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::fmt;
+use std::io::{self, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::str::{self, FromStr};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use futures::{Future, BoxFuture, Async, Poll};
 use futures::future;
-use rustfm_scrobble::{self, Scrobble};
+use hyper::Uri;
+use rustfm_scrobble::{self, Scrobble, ScrobbleBatch};
+use serde_json;
+use tokio_core::reactor::{Handle, Interval, Timeout};
 
-use metadata::{Track, Artist, Album, Metadata};
+use metadata::{cover, Track, Artist, Album, ImageSize, Metadata};
 use core::session::Session;
-use core::util::SpotifyId;
+use core::util::{now_ms, FileId, SpotifyId};
+use core::version;
+use skiplist::SkipList;
+use maloja::Maloja;
+use scrobble_db::ScrobbleDb;
+use webhook::Webhook;
 
 #[derive(Clone, Debug)]
 pub struct ScrobblerConfig {
@@ -14,6 +34,259 @@ pub struct ScrobblerConfig {
     pub api_secret: String,
     pub username: String,
     pub password: String,
+    pub session_key: Option<String>,
+    pub use_album_artist: bool,
+    pub threshold_debug: bool,
+    pub request_timeout: Option<Duration>,
+    pub cooldown: Duration,
+    pub min_context_tracks: usize,
+    pub now_playing_trigger: NowPlayingTrigger,
+    pub pause_timeout: Option<Duration>,
+    pub artist_separators: Vec<String>,
+    pub webhook: Option<Uri>,
+    pub webhook_now_playing: bool,
+    pub cover_size: ImageSize,
+    pub inhibit_command: Option<String>,
+    pub filter_command: Option<String>,
+    pub play_time_offset: Duration,
+    pub skiplist: Option<Arc<Mutex<SkipList>>>,
+    pub session_gap: Duration,
+    pub scrobble_when_muted: bool,
+    pub scrobble_delay: Duration,
+    pub scrobble_on_exit_only: bool,
+    pub dj_context_policy: DjContextPolicy,
+    pub maloja_url: Option<Uri>,
+    pub maloja_key: String,
+    pub now_playing_interval: Duration,
+    pub max_track_gap: Option<Duration>,
+    pub scrobble_db: Option<PathBuf>,
+    pub scrobble_db_plays: bool,
+}
+
+/// When a track's now-playing update is sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NowPlayingTrigger {
+    /// As soon as the track is selected, even if it's paused
+    Select,
+    /// Once the track is actually playing
+    Playing,
+}
+
+impl FromStr for NowPlayingTrigger {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "select" => Ok(NowPlayingTrigger::Select),
+            "playing" => Ok(NowPlayingTrigger::Playing),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What to do with tracks picked by Spotify rather than the listener, such as the AI DJ or radio
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DjContextPolicy {
+    Scrobble,
+    Skip,
+}
+
+impl FromStr for DjContextPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scrobble" => Ok(DjContextPolicy::Scrobble),
+            "skip" => Ok(DjContextPolicy::Skip),
+            _ => Err(()),
+        }
+    }
+}
+
+const DJ_CONTEXT_URI: &'static str = "spotify:playlist:37i9dQZF1EYkqdzj48dyYq";
+
+/// Whether the context is one Spotify fills dynamically: the DJ playlist or a radio station
+pub fn is_dj_context(context_uri: &str) -> bool {
+    context_uri == DJ_CONTEXT_URI || context_uri.starts_with("spotify:station:")
+}
+
+/// The parts of the active device's Spirc state that the scrobbler acts on
+#[derive(Clone, Debug)]
+pub struct PlaybackState {
+    pub track_id: SpotifyId,
+    pub track_index: u32,
+    pub context_tracks: usize,
+    pub dj_context: bool,
+    pub position_ms: u32,
+    pub playing: bool,
+    /// The playing device's volume is at zero
+    pub muted: bool,
+    pub local_track: Option<LocalTrack>,
+}
+
+/// Tags Spotify passes along for a local file, which has no Spotify metadata to look up
+#[derive(Clone, Debug)]
+pub struct LocalTrack {
+    pub uri: String,
+    pub artist: String,
+    pub album: String,
+    pub title: String,
+    pub duration: Duration,
+}
+
+impl LocalTrack {
+    /// Parses `spotify:local:<artist>:<album>:<title>:<seconds>`, whose parts are form-encoded
+    pub fn from_uri(uri: &str) -> Option<LocalTrack> {
+        if !uri.starts_with("spotify:local:") {
+            return None
+        }
+
+        let parts = uri["spotify:local:".len()..].split(':').map(form_decode).collect::<Vec<_>>();
+        if parts.len() != 4 || parts[0].is_empty() || parts[2].is_empty() {
+            return None
+        }
+
+        Some(LocalTrack {
+            uri: uri.to_owned(),
+            artist: parts[0].clone(),
+            album: parts[1].clone(),
+            title: parts[2].clone(),
+            duration: Duration::from_secs(parts[3].parse().unwrap_or(0)),
+        })
+    }
+
+    /// Local files have no gid, so a stand-in id is derived from the URI to tell plays apart
+    pub fn track_id(uri: &str) -> SpotifyId {
+        let mut raw = [0u8; 16];
+        for (half, chunk) in raw.chunks_mut(8).enumerate() {
+            let mut hasher = DefaultHasher::new();
+            (half, uri).hash(&mut hasher);
+            let hash = hasher.finish();
+            for (i, byte) in chunk.iter_mut().enumerate() {
+                *byte = (hash >> (56 - i * 8)) as u8;
+            }
+        }
+        SpotifyId::from_raw(&raw)
+    }
+}
+
+fn form_decode(part: &str) -> String {
+    let bytes = part.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = if bytes[i] == b'%' && i + 2 < bytes.len() {
+            str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+
+        match (bytes[i], escaped) {
+            (_, Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue
+            }
+            (b'+', None) => decoded.push(b' '),
+            (byte, None) => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Player state and counters shared with `Main`, which outlive any single session's scrobbler
+#[derive(Debug, Default)]
+pub struct ScrobblerStats {
+    pub connected: bool,
+    pub active: bool,
+    pub playing: bool,
+    pub position_ms: u32,
+    pub position_measured_at: u64,
+    pub current_track: Option<SpotifyId>,
+    pub current_cover_url: Option<String>,
+    pub listening_session: Option<u64>,
+    pub scrobbles: u64,
+    pub last_scrobble: Option<Instant>,
+    pub started_at: Option<Instant>,
+    /// Scrobbling is off, either because Last.fm rejected the credentials at startup or because
+    /// it gave up on us while running
+    pub lastfm_failed: bool,
+}
+
+/// What the filter command is told about a track, on its stdin
+#[derive(Serialize, Debug)]
+struct FilterInput<'a> {
+    uri: &'a str,
+    artist: &'a str,
+    credited_artist: &'a str,
+    title: &'a str,
+    album: &'a str,
+    duration_secs: u64,
+    cover_url: Option<&'a String>,
+    context_tracks: usize,
+    dj_context: bool,
+}
+
+/// Progress on the current track, written to the cache so a quick restart doesn't lose it
+#[derive(Serialize, Deserialize, Debug)]
+struct Checkpoint {
+    track_id: String,
+    play_time_secs: u64,
+    scrobbled: bool,
+    saved_at_ms: u64,
+}
+
+impl Checkpoint {
+    /// Whether this is recent enough progress on `track_id` to pick up again
+    fn applies_to(&self, track_id: SpotifyId, now_ms: u64) -> bool {
+        let age_ms = now_ms.saturating_sub(self.saved_at_ms);
+        self.track_id == track_id.to_base16() && age_ms <= CHECKPOINT_MAX_AGE_MS
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TrackMeta {
+    pub uri: String,
+    pub scrobble: Scrobble,
+    pub now_playing: Scrobble,
+    pub artist: String,
+    pub credited_artist: String,
+    pub title: String,
+    pub album: String,
+    pub cover_url: Option<String>,
+    pub duration: Duration,
+}
+
+/// A play held back from Last.fm, for shutdown or a clock fix. Monotonic time is kept so its
+/// timestamp comes out right even if the wall clock was wrong when it was held.
+#[derive(Clone, Debug)]
+pub struct HeldScrobble {
+    pub meta: TrackMeta,
+    held_at: Instant,
+    since_start: Duration,
+}
+
+impl HeldScrobble {
+    fn started_secs(&self) -> u64 {
+        play_start_secs(now_ms() as u64 / 1000, self.held_at.elapsed() + self.since_start)
+    }
+}
+
+/// A submission to Last.fm and the play it is for. The track may have changed by the time it
+/// completes, so the outcome is credited to this play rather than to whatever is current.
+struct PendingScrobble {
+    play: u64,
+    track_id: SpotifyId,
+    meta: TrackMeta,
+    future: BoxFuture<(), ScrobbleError>,
+}
+
+/// Whether the current play counts as scrobbled once a submission for `pending_play` succeeds
+fn scrobbled_after(pending_play: u64, current_play: u64, current_scrobbled: bool) -> bool {
+    pending_play == current_play || current_scrobbled
 }
 
 pub struct Scrobbler {
@@ -21,51 +294,373 @@ pub struct Scrobbler {
     scrobbler: rustfm_scrobble::Scrobbler,
 
     session: Box<Session>,
+    handle: Handle,
+    stats: Arc<Mutex<ScrobblerStats>>,
+    webhook: Option<Webhook>,
+    maloja: Option<Maloja>,
+    scrobble_db: Option<ScrobbleDb>,
     current_track_id: Option<SpotifyId>,
-    current_track_start: Option<Instant>,
-    current_track_meta: Option<Scrobble>,
+    /// Counts plays, so a submission can tell whether it's still for the current one
+    current_play: u64,
+    current_play_started: Instant,
+    current_track_played: Duration,
+    current_segment: Option<(u32, Instant)>,
+    paused_since: Option<Instant>,
+    listening_ended: bool,
+    restored_play_time: Duration,
+    current_track_meta: Option<TrackMeta>,
     current_track_scrobbled: bool,
+    current_track_inhibited: bool,
+    current_track_filtered: bool,
+    current_track_skipped: bool,
+    current_track_maloja_sent: bool,
+    failure_recorded_play: Option<u64>,
+    reauth_retried: bool,
+    /// A failed submission waiting to be sent again, and how many times in a row that happened
+    scrobble_retry: Option<(PendingScrobble, Timeout)>,
+    scrobble_failures: u32,
+    lastfm_disabled: bool,
+    inhibit_checked: Option<(Instant, bool)>,
+    filter_checked: Option<(SpotifyId, Instant, bool)>,
+    now_playing_sent: bool,
+    last_now_playing: Option<(SpotifyId, Instant)>,
+    current_track_index: u32,
+    current_context_tracks: usize,
+    current_dj_context: bool,
+    current_local_track: Option<LocalTrack>,
+    listening_session: u64,
+    last_listened: Option<Instant>,
+    meta_fetch_attempts: u32,
+    meta_fetch_failed: bool,
+    recent_scrobbles: HashMap<SpotifyId, Instant>,
+    restored_checkpoint: Option<Checkpoint>,
+    checkpoint_timer: Interval,
+    scrobble_delay: Option<Timeout>,
+    clock_sane: bool,
+    deferred_scrobbles: Vec<HeldScrobble>,
 
     auth_future: BoxFuture<(), rustfm_scrobble::ScrobblerError>,
+    auth_failed_at: Option<Instant>,
     new_track_future: BoxFuture<(), ()>,
     now_playing_future: BoxFuture<(), ScrobbleError>,
-    meta_fetch_future: BoxFuture<Scrobble, ScrobbleError>,
-    scrobble_future: Option<BoxFuture<(), ScrobbleError>>
+    meta_fetch_future: Box<Future<Item=TrackMeta, Error=ScrobbleError>>,
+    scrobble_future: Option<PendingScrobble>
 }
 
+const META_FETCH_ATTEMPTS: u32 = 3;
+const SCROBBLE_THRESHOLD_MAX_SECS: u64 = 240;
+const SCROBBLE_BATCH_MAX: usize = 50;
+const SCROBBLE_RETRY_MAX_SECS: u64 = 60;
+const POSITION_SLACK_MS: u64 = 2000;
+const INHIBIT_CACHE_SECS: u64 = 30;
+const CHECKPOINT_INTERVAL_SECS: u64 = 10;
+const CHECKPOINT_MAX_AGE_MS: u64 = 5 * 60 * 1000;
+const AUTH_RETRY_SECS: u64 = 60;
+const FILTER_CACHE_SECS: u64 = 60;
+const GATE_COMMAND_TIMEOUT_MS: u64 = 5000;
+const CLOCK_MAX_AHEAD_SECS: u64 = 10 * 365 * 24 * 60 * 60;
+
 #[derive(Debug)]
-pub struct ScrobbleError {
-    msg: String
+pub enum ScrobbleError {
+    /// Looking up track, artist or album metadata from Spotify failed
+    Metadata(String),
+    /// Last.fm refused the configured API key, secret or account credentials
+    Auth(String),
+    /// The Last.fm API rejected or failed a request
+    Lastfm(String),
 }
 
 impl ScrobbleError {
+    /// Last.fm error 9, "Invalid session key - Please re-authenticate"
+    fn is_session_expired(&self) -> bool {
+        match *self {
+            ScrobbleError::Lastfm(ref msg) => {
+                let lower = msg.to_lowercase();
+                lower.contains("session key") || lower.contains("re-authenticate")
+            }
+            _ => false
+        }
+    }
+}
+
+impl fmt::Display for ScrobbleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ScrobbleError::Metadata(ref msg) => write!(f, "Metadata error: {}", msg),
+            ScrobbleError::Auth(ref msg) => write!(f, "Last.fm authentication error: {}", msg),
+            ScrobbleError::Lastfm(ref msg) => write!(f, "Last.fm error: {}", msg),
+        }
+    }
+}
+
+/// Authenticates against Last.fm with the configured account, returning the session key.
+/// Used at startup so bad credentials are reported before any music is played.
+pub fn authenticate(config: &ScrobblerConfig) -> Result<String, ScrobbleError> {
+    let mut scrobbler = rustfm_scrobble::Scrobbler::new(&config.api_key, &config.api_secret);
+
+    match scrobbler.authenticate_with_password(&config.username, &config.password) {
+        Ok(session) => Ok(session.key),
+        Err(err) => {
+            let msg = format!("{}", err);
+            let lower = msg.to_lowercase();
+
+            let reason = if lower.contains("api key") {
+                "the Last.fm API key is invalid"
+            } else if lower.contains("signature") {
+                "the Last.fm API secret does not match the API key"
+            } else if lower.contains("authentication failed") {
+                "the Last.fm username or password is wrong"
+            } else {
+                // Not a rejection, so it may well work once the network is up
+                let reason = "Last.fm could not be reached or returned an unexpected error";
+                return Err(ScrobbleError::Lastfm(format!("{} ({})", reason, msg)))
+            };
 
-    pub fn new(msg: String) -> ScrobbleError {
-        ScrobbleError {
-            msg: msg
+            Err(ScrobbleError::Auth(format!("{} ({})", reason, msg)))
         }
     }
+}
+
+/// Submits a single scrobble with an authenticated config, outside of any playback, so the
+/// whole path to the user's Last.fm profile can be checked
+pub fn submit_test_scrobble(config: &ScrobblerConfig, artist: &str, title: &str) -> Result<(), ScrobbleError> {
+    let mut scrobbler = rustfm_scrobble::Scrobbler::new(&config.api_key, &config.api_secret);
+    if let Some(ref session_key) = config.session_key {
+        scrobbler.authenticate_with_session_key(session_key);
+    }
+
+    scrobbler.scrobble(&Scrobble::new(artist, title, ""))
+        .map(|_| ())
+        .map_err(|err| ScrobbleError::Lastfm(format!("{:?}", err)))
+}
+
+/// Boards without a battery-backed clock can boot with a date in 1970 or far in the future. A
+/// clock set before this build was made, or years after it, is assumed to be wrong.
+fn clock_plausible() -> bool {
+    let now = now_ms() / 1000;
+    let built = version::build_time() as i64;
+    now >= built && now <= built + CLOCK_MAX_AHEAD_SECS as i64
+}
+
+/// Runs an inhibit or filter command, with `input` on its stdin if given, returning whether it
+/// rejected the scrobble. One that hangs is killed rather than holding up playback for good.
+fn run_gate_command(command: &str, input: Option<&str>) -> io::Result<bool> {
+    let stdin = if input.is_some() { Stdio::piped() } else { Stdio::null() };
+    let mut child = try!(Command::new(command)
+        .stdin(stdin)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn());
+    // A command that decides without reading its input may have closed the pipe already
+    if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+        let _ = stdin.write_all(input.as_bytes());
+    }
+
+    let started_at = Instant::now();
+    loop {
+        if let Some(status) = try!(child.try_wait()) {
+            return Ok(!status.success())
+        }
+
+        if started_at.elapsed() > Duration::from_millis(GATE_COMMAND_TIMEOUT_MS) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Picks the cover in the requested size, or any cover if the album doesn't have that size
+fn album_cover(album: &Album, size: ImageSize) -> Option<FileId> {
+    album.cover_sizes.iter()
+        .find(|&&(ref cover_size, _)| *cover_size == size)
+        .map(|&(_, file_id)| file_id)
+        .or(album.covers.first().cloned())
+}
+
+/// Cuts a credit like "A feat. B" or "A, B & C" down to the artist before the first separator
+fn primary_artist<'a>(artist: &'a str, separators: &[String]) -> &'a str {
+    let split_at = separators.iter()
+        .filter_map(|separator| artist.find(separator.as_str()))
+        .filter(|&index| index > 0)
+        .min();
+
+    match split_at {
+        Some(index) => artist[..index].trim(),
+        None => artist
+    }
+}
+
+/// Whether the same track as before is a fresh play of it. The same track queued twice in a row
+/// only differs by its place in the context, but shuffling or queueing moves the playing track
+/// too, so it must also have started over: be back at the start, or behind where the current
+/// stretch of playback began.
+fn replayed(index_before: u32, segment_start_ms: Option<u32>, state: &PlaybackState) -> bool {
+    if state.track_index == index_before {
+        return false
+    }
+    if state.position_ms as u64 <= POSITION_SLACK_MS {
+        return true
+    }
+
+    match segment_start_ms {
+        Some(start_position_ms) => state.position_ms < start_position_ms,
+        None => false
+    }
+}
+
+/// Whether a playback report starts a new play, ending (and perhaps scrobbling) the current one.
+/// Another track always does, even part-way in, as gapless and crossfaded transitions are reported.
+fn starts_new_play(current_track_id: Option<SpotifyId>, index_before: u32, segment_start_ms: Option<u32>,
+                   state: &PlaybackState) -> bool {
+    if state.position_ms == 0 {
+        return true
+    }
+
+    match current_track_id {
+        Some(id) => id != state.track_id || replayed(index_before, segment_start_ms, state),
+        None => true
+    }
+}
+
+/// Unix time, in seconds, at which a play that began `since_start` ago started
+fn play_start_secs(now_secs: u64, since_start: Duration) -> u64 {
+    now_secs.saturating_sub(since_start.as_secs())
+}
+
+/// How long to wait before retrying a scrobble that failed `failures` times in a row. It backs off
+/// exponentially, so an outage isn't met with a request on every poll.
+fn scrobble_retry_delay(failures: u32) -> Duration {
+    Duration::from_secs(cmp::min(1 << cmp::min(failures, 6), SCROBBLE_RETRY_MAX_SECS))
+}
+
+/// Last.fm's rule: a track counts once half of it has played, or four minutes for long tracks.
+/// An unknown (zero) duration would make every track count at once, so it gets the four minutes.
+fn scrobble_threshold(duration: Duration) -> Duration {
+    let max = Duration::from_secs(SCROBBLE_THRESHOLD_MAX_SECS);
+    if duration == Duration::from_secs(0) {
+        return max
+    }
+    cmp::min(duration / 2, max)
+}
+
+/// Play time between two position reports `elapsed` apart on the wall clock. The position moving
+/// further than the wall clock allows, or backwards, means a seek, and then only the time that
+/// actually passed is counted.
+fn credited_play_time(start_position_ms: u64, position_ms: u64, elapsed: Duration) -> Duration {
+    let elapsed_ms = elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64;
+
+    if position_ms >= start_position_ms && position_ms - start_position_ms <= elapsed_ms + POSITION_SLACK_MS {
+        Duration::from_millis(position_ms - start_position_ms)
+    } else {
+        elapsed
+    }
+}
+
+/// Builds what is scrobbled for a Spotify track. `album` is `None` when its lookup failed, and
+/// the track is then scrobbled without an album or cover rather than not at all.
+fn catalogue_track_meta(track_id: SpotifyId, title: &str, duration_ms: i32, artist_name: &str,
+                        album: Option<&Album>, cover_size: ImageSize, artist_separators: &[String]) -> TrackMeta {
+    let duration_ms = if duration_ms > 0 { duration_ms as u64 } else { 0 };
+    let cover_url = album.and_then(|album| album_cover(album, cover_size)).map(cover::url);
+    let album_name = album.map(|album| album.name.clone()).unwrap_or_default();
+
+    let primary_artist = primary_artist(artist_name, artist_separators);
 
+    TrackMeta {
+        uri: format!("spotify:track:{}", track_id.to_base62()),
+        scrobble: Scrobble::new(primary_artist, title, &album_name),
+        now_playing: Scrobble::new(artist_name, title, &album_name),
+        artist: primary_artist.to_owned(),
+        credited_artist: artist_name.to_owned(),
+        title: title.to_owned(),
+        album: album_name,
+        cover_url: cover_url,
+        duration: Duration::from_millis(duration_ms),
+    }
 }
 
 impl Scrobbler {
 
-    pub fn new(config: ScrobblerConfig, session: Session) -> Scrobbler {
+    pub fn new(config: ScrobblerConfig, session: Session, stats: Arc<Mutex<ScrobblerStats>>,
+               handle: Handle) -> Scrobbler {
+        let restored_checkpoint = session.cache()
+            .and_then(|cache| cache.scrobbler_state())
+            .and_then(|contents| serde_json::from_str(&contents).ok());
+        let checkpoint_timer = Interval::new(Duration::from_secs(CHECKPOINT_INTERVAL_SECS), &handle).unwrap();
+
+        let webhook = config.webhook.clone().map(|uri| Webhook::new(uri, &handle));
+        let maloja = config.maloja_url.clone().map(|uri| Maloja::new(uri, config.maloja_key.clone(), &handle));
+        let scrobble_db = config.scrobble_db.as_ref().and_then(|path| {
+            match ScrobbleDb::open(path) {
+                Ok(db) => Some(db),
+                Err(err) => {
+                    error!("Could not open scrobble database {}, not recording scrobbles: {}", path.display(), err);
+                    None
+                }
+            }
+        });
+
         let mut scrobbler = Scrobbler {
             session: Box::new(session),
+            handle: handle,
+            stats: stats,
+            webhook: webhook,
+            maloja: maloja,
+            scrobble_db: scrobble_db,
             scrobbler: rustfm_scrobble::Scrobbler::new(&config.api_key, &config.api_secret),
             current_track_id: None,
-            current_track_start: None,
+            current_play: 0,
+            current_play_started: Instant::now(),
+            current_track_played: Duration::from_secs(0),
+            current_segment: None,
+            paused_since: None,
+            listening_ended: false,
+            restored_play_time: Duration::from_secs(0),
             current_track_meta: None,
             current_track_scrobbled: false,
+            current_track_inhibited: false,
+            current_track_filtered: false,
+            current_track_skipped: false,
+            current_track_maloja_sent: false,
+            failure_recorded_play: None,
+            reauth_retried: false,
+            scrobble_retry: None,
+            scrobble_failures: 0,
+            lastfm_disabled: false,
+            inhibit_checked: None,
+            filter_checked: None,
+            now_playing_sent: false,
+            last_now_playing: None,
+            current_track_index: 0,
+            current_context_tracks: 0,
+            current_dj_context: false,
+            current_local_track: None,
+            listening_session: 0,
+            last_listened: None,
+            meta_fetch_attempts: 0,
+            meta_fetch_failed: false,
+            recent_scrobbles: HashMap::new(),
+            restored_checkpoint: restored_checkpoint,
+            checkpoint_timer: checkpoint_timer,
+            scrobble_delay: None,
+            clock_sane: clock_plausible(),
+            deferred_scrobbles: Vec::new(),
             auth_future: future::empty().boxed(),
+            auth_failed_at: None,
             new_track_future: future::empty().boxed(),
             now_playing_future: future::empty().boxed(),
-            meta_fetch_future: future::empty().boxed(),
+            meta_fetch_future: Box::new(future::empty()),
             scrobble_future: None,
             config: config
         };
 
+        if !scrobbler.clock_sane {
+            warn!("System clock reads {}s since the epoch, which can't be right for this build; \
+                   holding back scrobbles and checkpoints until it is corrected", now_ms() / 1000);
+        }
+
         scrobbler.start_auth();
         scrobbler
     }
@@ -75,61 +670,258 @@ impl Scrobbler {
     }
 
     pub fn auth(&mut self) -> BoxFuture<(), rustfm_scrobble::ScrobblerError> {
+        if let Some(ref session_key) = self.config.session_key {
+            self.scrobbler.authenticate_with_session_key(session_key);
+            return future::ok(()).boxed();
+        }
+
         match self.scrobbler.authenticate_with_password(&self.config.username, &self.config.password) {
             Ok(_) => future::ok(()),
             Err(err) => future::err(err)
         }.boxed()
     }
 
-    pub fn update_current_track(&mut self, track_id: SpotifyId, force_new_track: bool) {
-        if !force_new_track {
-            let mut new_track_detected = false;
-            match self.current_track_id {
-                None => { 
-                    new_track_detected = true;
-                },
-                Some(id) => {
-                    if id != track_id {
-                        new_track_detected = true;
-                    }
+    pub fn update_current_track(&mut self, state: &PlaybackState) {
+        let track_id = state.track_id;
+        let segment_start_ms = self.current_segment.map(|(start_position_ms, _)| start_position_ms);
+
+        // Resuming after a pause that ended the listening session starts the track afresh
+        let resumed = self.listening_ended && self.listening(state);
+        if !resumed && !starts_new_play(self.current_track_id, self.current_track_index, segment_start_ms, state) {
+            self.update_position(state);
+            self.maybe_send_now_playing();
+            return
+        }
+
+        if self.can_scrobble_track() {
+            self.start_scrobble();
+        }
+
+        if self.config.threshold_debug {
+            self.log_threshold_decision();
+        }
+
+        self.current_track_index = state.track_index;
+        self.current_context_tracks = state.context_tracks;
+        self.current_dj_context = state.dj_context;
+        self.current_local_track = state.local_track.clone();
+        self.new_track_future = self.set_new_track(track_id);
+        self.update_position(state);
+        self.maybe_send_now_playing();
+    }
+
+    /// The device playing the track went inactive, so no more positions will be reported for it.
+    /// Play time up to now is credited and the track counts as paused from here.
+    pub fn playback_stopped(&mut self) {
+        if let Some((_, started_at)) = self.current_segment.take() {
+            self.current_track_played += started_at.elapsed();
+        }
+        if self.paused_since.is_none() {
+            self.paused_since = Some(Instant::now());
+        }
+    }
+
+    /// Whether the state counts as listening; unless configured otherwise, muted playback is
+    /// treated like a pause
+    fn listening(&self, state: &PlaybackState) -> bool {
+        state.playing && (self.config.scrobble_when_muted || !state.muted)
+    }
+
+    /// Credits play time from the reported position rather than the wall clock, so pauses and
+    /// seeks are accounted for. A jump the wall clock can't explain was a seek, in which case
+    /// only the time actually spent listening is counted.
+    fn update_position(&mut self, state: &PlaybackState) {
+        if let Some((start_position_ms, started_at)) = self.current_segment.take() {
+            let elapsed = started_at.elapsed();
+            let elapsed_ms = elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64;
+            let position_ms = state.position_ms as u64;
+            let start_position_ms = start_position_ms as u64;
+
+            if let Some(max_gap) = self.config.max_track_gap {
+                let drift_ms = (position_ms as i64 - start_position_ms as i64 - elapsed_ms as i64).abs() as u64;
+                if drift_ms > max_gap.as_secs() * 1000 {
+                    warn!("Track {} position moved {}ms -> {}ms over {}ms of playback, off by {}ms \
+                           (seek, missed events or a clock problem)",
+                          self.current_track_id.map(|id| id.to_base16()).unwrap_or_default(),
+                          start_position_ms, position_ms, elapsed_ms, drift_ms);
                 }
             }
 
-            if !new_track_detected {
-                return
-            }
+            self.current_track_played += credited_play_time(start_position_ms, position_ms, elapsed);
+        }
+
+        if self.listening(state) {
+            self.current_segment = Some((state.position_ms, Instant::now()));
+            self.paused_since = None;
+            self.mark_listening();
+        } else if self.paused_since.is_none() {
+            self.paused_since = Some(Instant::now());
+        }
+    }
+
+    /// Listening sessions group plays for analysis only, Last.fm doesn't know about them. One
+    /// ends once nothing has played for `session_gap`, and is identified by its start time.
+    fn mark_listening(&mut self) {
+        let gap = self.config.session_gap;
+        let new_session = self.last_listened.map_or(true, |last_listened| last_listened.elapsed() > gap);
+        self.last_listened = Some(Instant::now());
+
+        if new_session {
+            self.listening_session = now_ms() as u64 / 1000;
+            info!("Listening session {} started", self.listening_session);
+            self.stats.lock().unwrap().listening_session = Some(self.listening_session);
+        }
+    }
+
+    /// Treats a pause longer than `pause_timeout` like a stop, scrobbling the track if it qualifies
+    fn check_pause_timeout(&mut self) {
+        let timed_out = match (self.config.pause_timeout, self.paused_since) {
+            (Some(timeout), Some(paused_since)) => paused_since.elapsed() > timeout,
+            _ => false
+        };
+        if !timed_out || self.listening_ended || self.current_track_id.is_none() {
+            return
         }
 
+        info!("Paused for over {}s, ending the listening session", self.config.pause_timeout.unwrap().as_secs());
         if self.can_scrobble_track() {
             self.start_scrobble();
         }
 
-        self.new_track_future = self.set_new_track(track_id);
+        if self.config.threshold_debug {
+            self.log_threshold_decision();
+        }
+
+        self.listening_ended = true;
     }
 
     pub fn set_new_track(&mut self, track_id: SpotifyId) -> BoxFuture<(), ()> {
         self.current_track_id = Some(track_id);
-        self.current_track_start = Some(Instant::now());
+        self.current_play += 1;
+        self.current_play_started = Instant::now();
+        self.current_track_played = Duration::from_secs(0);
+        self.current_segment = None;
+        self.paused_since = None;
+        self.listening_ended = false;
+        self.restored_play_time = Duration::from_secs(0);
         self.current_track_meta = None;
         self.current_track_scrobbled = false;
+        self.current_track_inhibited = false;
+        self.current_track_filtered = false;
+        self.current_track_skipped = false;
+        self.current_track_maloja_sent = false;
+        self.now_playing_sent = false;
+        self.scrobble_delay = None;
+        self.meta_fetch_attempts = 0;
+        self.meta_fetch_failed = false;
+        self.restore_checkpoint(track_id);
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.current_track = Some(track_id);
+            stats.current_cover_url = None;
+        }
 
         future::ok(()).boxed()
     }
 
-    pub fn get_track_meta(&mut self, track_id: SpotifyId) -> BoxFuture<Scrobble, ScrobbleError> {
+    /// Builds the scrobble for a local file from its tags, as there is nothing to look up
+    fn local_track_meta(&self, local: &LocalTrack) -> TrackMeta {
+        let primary_artist = primary_artist(&local.artist, &self.config.artist_separators);
+
+        TrackMeta {
+            uri: local.uri.clone(),
+            scrobble: Scrobble::new(primary_artist, &local.title, &local.album),
+            now_playing: Scrobble::new(&local.artist, &local.title, &local.album),
+            artist: primary_artist.to_owned(),
+            credited_artist: local.artist.clone(),
+            title: local.title.clone(),
+            album: local.album.clone(),
+            cover_url: None,
+            duration: local.duration,
+        }
+    }
+
+    pub fn get_track_meta(&mut self, track_id: SpotifyId) -> Box<Future<Item=TrackMeta, Error=ScrobbleError>> {
+        if let Some(ref local) = self.current_local_track {
+            return Box::new(future::ok(self.local_track_meta(local)))
+        }
+
         let session = self.session.clone();
+        let use_album_artist = self.config.use_album_artist;
+        let artist_separators = self.config.artist_separators.clone();
+        let cover_size = self.config.cover_size.clone();
+        self.meta_fetch_attempts += 1;
+
+        let fetch = Track::get(&session, track_id).map_err(move |err| {
+            ScrobbleError::Metadata(format!("track {}: {:?}", track_id.to_base16(), err))
+        }).and_then(move |track| {
+            let artist_id = match track.artists.first() {
+                Some(artist_id) => *artist_id,
+                None => {
+                    let msg = format!("track {} has no artists", track_id.to_base16());
+                    return future::err(ScrobbleError::Metadata(msg)).boxed()
+                }
+            };
 
-        Track::get(&session, track_id).and_then(move |track| {
-            let track_name = track.clone().name;
-            let artist = *track.artists.first().expect("No artists");
-            Artist::get(&session, artist).map(move |artist| (track_name, artist.name.clone(), track, session))
-        }).and_then(move |(track_name, artist_name, track_meta, session)| {
-            Album::get(&session, track_meta.album).map(|album| (track_name, artist_name, album.name.clone()))
-        }).map_err(move |err| {
-            ScrobbleError::new(format!("{:?}", err).to_owned())
-        }).and_then(move |(track, artist, album)| {
-            future::ok(Scrobble::new(&artist, &track, &album))
-        }).boxed()
+            let artist = Artist::get(&session, artist_id).map_err(move |err| {
+                ScrobbleError::Metadata(format!("artist {}: {:?}", artist_id.to_base16(), err))
+            });
+
+            // The album is only an enrichment, so a failed lookup shouldn't cost the scrobble
+            let album_id = track.album;
+            let album = Album::get(&session, album_id).then(move |result| -> Result<Option<Album>, ScrobbleError> {
+                match result {
+                    Ok(album) => Ok(Some(album)),
+                    Err(err) => {
+                        warn!("Album metadata fetch failed for {}, scrobbling without album: {:?}",
+                              album_id.to_base16(), err);
+                        Ok(None)
+                    }
+                }
+            });
+
+            let session = session.clone();
+            artist.join(album).and_then(move |(artist, album)| {
+                let album_artist_id = match album {
+                    Some(ref album) if use_album_artist => album.artists.first().cloned(),
+                    _ => None,
+                };
+
+                let artist_name = match album_artist_id {
+                    Some(album_artist_id) if album_artist_id != artist.id => {
+                        Artist::get(&session, album_artist_id).then(move |result| -> Result<String, ScrobbleError> {
+                            match result {
+                                Ok(album_artist) => Ok(album_artist.name),
+                                Err(err) => {
+                                    warn!("Album artist metadata fetch failed for {}, using track artist: {:?}",
+                                          album_artist_id.to_base16(), err);
+                                    Ok(artist.name)
+                                }
+                            }
+                        }).boxed()
+                    },
+                    _ => future::ok(artist.name).boxed(),
+                };
+
+                artist_name.map(move |artist_name| {
+                    catalogue_track_meta(track_id, &track.name, track.duration, &artist_name, album.as_ref(),
+                                         cover_size, &artist_separators)
+                })
+            }).boxed()
+        });
+
+        // A timed out lookup is reported as a metadata error, so it is retried like any other
+        match self.config.request_timeout {
+            Some(timeout) => {
+                let timer = Timeout::new(timeout, &self.handle).unwrap();
+                let timer = timer.then(move |_| -> Result<TrackMeta, ScrobbleError> {
+                    Err(ScrobbleError::Metadata(format!("track {}: timed out", track_id.to_base16())))
+                });
+
+                Box::new(fetch.select(timer).map(|(meta, _)| meta).map_err(|(err, _)| err))
+            },
+            None => Box::new(fetch)
+        }
     }
 
     pub fn send_now_playing(&self, track: &Scrobble) -> BoxFuture<(), ScrobbleError> {
@@ -137,21 +929,212 @@ impl Scrobbler {
 
         match self.scrobbler.now_playing(track) {
             Ok(_) => future::ok(()),
-            Err(err) => future::err(ScrobbleError::new(format!("{:?}", err)))
+            Err(err) => future::err(ScrobbleError::Lastfm(format!("{:?}", err)))
         }.boxed()
     }
 
-    pub fn start_scrobble(&mut self) {
-        self.scrobble_future = match self.current_track_meta {
-            Some(ref meta) => {
-                let scrobble = &meta.clone();
-                Some(self.send_scrobble(scrobble))
+    fn maybe_send_now_playing(&mut self) {
+        let playing = self.current_segment.is_some();
+        if self.now_playing_sent || self.current_track_skipped || self.lastfm_disabled || (self.config.now_playing_trigger == NowPlayingTrigger::Playing && !playing) {
+            return
+        }
+
+        let scrobble = match self.current_track_meta {
+            Some(ref meta) => meta.now_playing.clone(),
+            None => return
+        };
+
+        // A restart of the same track shortly after is left showing the update already sent
+        if let (Some(track_id), Some((last_track_id, sent_at))) = (self.current_track_id, self.last_now_playing) {
+            if track_id == last_track_id && sent_at.elapsed() < self.config.now_playing_interval {
+                debug!("Not repeating now-playing for track {} so soon", track_id.to_base16());
+                self.now_playing_sent = true;
+                return
+            }
+        }
+        self.last_now_playing = self.current_track_id.map(|track_id| (track_id, Instant::now()));
+
+        self.now_playing_future = self.send_now_playing(&scrobble);
+        self.now_playing_sent = true;
+
+        if self.config.webhook_now_playing {
+            if let (Some(webhook), Some(meta)) = (self.webhook.as_ref(), self.current_track_meta.as_ref()) {
+                webhook.send("now_playing", &meta.credited_artist, meta, self.listening_session);
+            }
+        }
+
+        if self.config.scrobble_db_plays {
+            if let Some(meta) = self.current_track_meta.as_ref() {
+                self.record_in_db(meta, &meta.credited_artist, "playing");
+            }
+        }
+    }
+
+    fn record_in_db(&self, meta: &TrackMeta, artist: &str, status: &str) {
+        if let Some(ref db) = self.scrobble_db {
+            db.record(now_ms() / 1000, artist, meta, "lastfm", status);
+        }
+    }
+
+    /// Failed submissions are retried on every poll, so only the first failure of a play gets a row
+    fn record_failure_in_db(&mut self, pending: &PendingScrobble) {
+        if self.failure_recorded_play == Some(pending.play) {
+            return
+        }
+        self.failure_recorded_play = Some(pending.play);
+        self.record_in_db(&pending.meta, &pending.meta.artist, "failed");
+    }
+
+    /// Hands over the scrobbles held back for shutdown or a clock fix, so a reconnect, which
+    /// replaces this scrobbler, doesn't lose them
+    pub fn take_deferred(&mut self) -> Vec<HeldScrobble> {
+        mem::replace(&mut self.deferred_scrobbles, Vec::new())
+    }
+
+    pub fn restore_deferred(&mut self, scrobbles: Vec<HeldScrobble>) {
+        self.deferred_scrobbles.extend(scrobbles);
+    }
+
+    /// Scrobbles the current track if it already qualifies, since shutting down would lose it
+    pub fn flush(&mut self) {
+        if let Some((pending, _)) = self.scrobble_retry.take() {
+            warn!("Scrobble waiting to be retried is lost at shutdown: {:?}", pending.meta.scrobble);
+        }
+
+        if self.can_scrobble_track() {
+            self.start_scrobble();
+        }
+
+        let result = self.scrobble_future.take().map(|mut pending| {
+            let result = pending.future.poll();
+            (pending, result)
+        });
+        match result {
+            Some((pending, Ok(Async::Ready(_)))) => {
+                self.scrobble_succeeded(&pending);
+                self.checkpoint();
+            },
+            Some((_, Ok(Async::NotReady))) => warn!("Scrobble still in flight at shutdown, it may be lost"),
+            Some((pending, Err(err))) => {
+                error!("Scrobbling error: {:?}", err);
+                self.record_failure_in_db(&pending);
             },
-            None => {
+            None => ()
+        }
+
+        if self.deferred_scrobbles.is_empty() {
+            return
+        }
+
+        if self.clock_sane {
+            info!("Submitting {} scrobbles held back until shutdown", self.deferred_scrobbles.len());
+            self.submit_deferred();
+        } else {
+            warn!("Dropping {} scrobbles held back because the system clock is wrong",
+                  self.deferred_scrobbles.len());
+        }
+    }
+
+    fn scrobble_succeeded(&mut self, pending: &PendingScrobble) {
+        self.current_track_scrobbled = scrobbled_after(pending.play, self.current_play, self.current_track_scrobbled);
+        self.record_scrobble(pending.track_id);
+
+        if let Some(ref webhook) = self.webhook {
+            webhook.send("scrobble", &pending.meta.artist, &pending.meta, self.listening_session);
+        }
+        self.record_in_db(&pending.meta, &pending.meta.artist, "scrobbled");
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.scrobbles += 1;
+        stats.last_scrobble = Some(Instant::now());
+    }
+
+    /// Waits out the configured delay once a track qualifies before submitting it, so another
+    /// scrobbler on the account gets in first. A track that ends meanwhile is submitted straight away.
+    fn start_delayed_scrobble(&mut self) {
+        if self.config.scrobble_delay == Duration::from_secs(0) {
+            self.start_scrobble();
+            return
+        }
+
+        if self.scrobble_delay.is_none() {
+            debug!("Delaying scrobble by {}s", self.config.scrobble_delay.as_secs());
+            self.scrobble_delay = Some(Timeout::new(self.config.scrobble_delay, &self.handle).unwrap());
+        }
+
+        let elapsed = match self.scrobble_delay {
+            Some(ref mut delay) => delay.poll().map(|result| result.is_ready()).unwrap_or(true),
+            None => false
+        };
+        if elapsed {
+            self.scrobble_delay = None;
+            self.start_scrobble();
+        }
+    }
+
+    pub fn start_scrobble(&mut self) {
+        if self.current_track_meta.is_some() && self.inhibited() {
+            info!("Scrobble of track {} inhibited by {}",
+                  self.current_track_id.map(|id| id.to_base16()).unwrap_or_default(),
+                  self.config.inhibit_command.as_ref().unwrap());
+            self.current_track_inhibited = true;
+            return
+        }
+
+        if self.current_track_meta.is_some() && self.filtered() {
+            info!("Scrobble of track {} rejected by {}",
+                  self.current_track_id.map(|id| id.to_base16()).unwrap_or_default(),
+                  self.config.filter_command.as_ref().unwrap());
+            self.current_track_filtered = true;
+            return
+        }
+
+        // Last.fm would get the wrong time, so wait for the clock to be fixed
+        if !self.clock_sane || self.config.scrobble_on_exit_only {
+            if let Some(ref meta) = self.current_track_meta {
+                if self.config.scrobble_on_exit_only {
+                    info!("Holding back scrobble until shutdown: {:?}", meta.scrobble);
+                } else {
+                    info!("Holding back scrobble until the system clock is corrected: {:?}", meta.scrobble);
+                }
+                self.deferred_scrobbles.push(HeldScrobble {
+                    meta: meta.clone(),
+                    held_at: Instant::now(),
+                    since_start: self.since_play_start(),
+                });
+                self.current_track_scrobbled = true;
+                return
+            }
+        }
+
+        let (track_id, mut meta) = match (self.current_track_id, self.current_track_meta.clone()) {
+            (Some(track_id), Some(meta)) => (track_id, meta),
+            _ => {
                 error!("No track meta-data available for scrobble");
-                None
+                return
             }
+        };
+        // Last.fm would otherwise use the time of submission, which --scrobble-delay pushes back
+        meta.scrobble.with_timestamp(self.play_started_secs());
+
+        // Last.fm submissions are retried, but Maloja only needs to hear about the play once
+        if let (Some(maloja), false) = (self.maloja.as_ref(), self.current_track_maloja_sent) {
+            maloja.scrobble(&meta);
+            self.current_track_maloja_sent = true;
         }
+
+        let play = self.current_play;
+        self.submit_scrobble(play, track_id, meta);
+    }
+
+    fn submit_scrobble(&mut self, play: u64, track_id: SpotifyId, meta: TrackMeta) {
+        let future = self.send_scrobble(&meta.scrobble);
+        self.scrobble_future = Some(PendingScrobble {
+            play: play,
+            track_id: track_id,
+            meta: meta,
+            future: future,
+        });
     }
 
     pub fn send_scrobble(&self, scrobble: &Scrobble) -> BoxFuture<(), ScrobbleError> {
@@ -159,12 +1142,136 @@ impl Scrobbler {
 
         match self.scrobbler.scrobble(scrobble) {
             Ok(_) => future::ok(()),
-            Err(err) => future::err(ScrobbleError::new(format!("{:?}", err)))
+            Err(err) => future::err(ScrobbleError::Lastfm(format!("{:?}", err)))
         }.boxed()
     }
 
+    /// Asks the inhibit command whether scrobbling is wanted right now; a non-zero exit means no.
+    /// The answer is reused for a short while so the command isn't run on every check.
+    fn inhibited(&mut self) -> bool {
+        let command = match self.config.inhibit_command {
+            Some(ref command) => command.clone(),
+            None => return false
+        };
+
+        if let Some((checked_at, inhibited)) = self.inhibit_checked {
+            if checked_at.elapsed() < Duration::from_secs(INHIBIT_CACHE_SECS) {
+                return inhibited
+            }
+        }
+
+        let inhibited = match run_gate_command(&command, None) {
+            Ok(inhibited) => inhibited,
+            Err(err) => {
+                warn!("Could not run scrobble inhibit command {}, scrobbling anyway: {}", command, err);
+                false
+            }
+        };
+        self.inhibit_checked = Some((Instant::now(), inhibited));
+        inhibited
+    }
+
+    /// Asks the filter command whether the current track should be scrobbled, passing its
+    /// metadata as JSON on stdin; a non-zero exit means no. A command that can't be run or takes
+    /// too long doesn't block the scrobble. The answer for a track is reused for a while, so
+    /// repeated plays don't run it again.
+    fn filtered(&mut self) -> bool {
+        let (command, track_id) = match (self.config.filter_command.as_ref(), self.current_track_id) {
+            (Some(command), Some(track_id)) => (command.clone(), track_id),
+            _ => return false
+        };
+
+        if let Some((checked_id, checked_at, filtered)) = self.filter_checked {
+            if checked_id == track_id && checked_at.elapsed() < Duration::from_secs(FILTER_CACHE_SECS) {
+                return filtered
+            }
+        }
+
+        let input = match self.current_track_meta {
+            Some(ref meta) => serde_json::to_string(&FilterInput {
+                uri: &meta.uri,
+                artist: &meta.artist,
+                credited_artist: &meta.credited_artist,
+                title: &meta.title,
+                album: &meta.album,
+                duration_secs: meta.duration.as_secs(),
+                cover_url: meta.cover_url.as_ref(),
+                context_tracks: self.current_context_tracks,
+                dj_context: self.current_dj_context,
+            }).unwrap(),
+            None => return false
+        };
+
+        let filtered = match run_gate_command(&command, Some(&input)) {
+            Ok(filtered) => filtered,
+            Err(err) => {
+                warn!("Scrobble filter command {} failed, scrobbling anyway: {}", command, err);
+                false
+            }
+        };
+        self.filter_checked = Some((track_id, Instant::now(), filtered));
+        filtered
+    }
+
+    /// The Last.fm session was revoked or expired: log in again once and retry the scrobble,
+    /// returning whether it is being retried. If that doesn't help, stop submitting so every
+    /// later track doesn't fail the same way.
+    fn handle_expired_session(&mut self, pending: &PendingScrobble) -> bool {
+        if self.reauth_retried {
+            error!("Last.fm still rejects the session after logging in again, scrobbling is disabled until restart");
+            self.disable_lastfm();
+            return false
+        }
+
+        warn!("Last.fm session is no longer valid, logging in again");
+        match self.scrobbler.authenticate_with_password(&self.config.username, &self.config.password) {
+            Ok(_) => {
+                info!("Logged in to Last.fm again, retrying the scrobble");
+                self.reauth_retried = true;
+                self.submit_scrobble(pending.play, pending.track_id, pending.meta.clone());
+                true
+            }
+            Err(err) => {
+                error!("Could not log in to Last.fm again, scrobbling is disabled until restart: {:?}", err);
+                self.disable_lastfm();
+                false
+            }
+        }
+    }
+
+    fn schedule_scrobble_retry(&mut self, pending: PendingScrobble) {
+        if let Some((dropped, _)) = self.scrobble_retry.take() {
+            warn!("Giving up on retrying an earlier scrobble: {:?}", dropped.meta.scrobble);
+        }
+
+        self.scrobble_failures += 1;
+        let delay = scrobble_retry_delay(self.scrobble_failures);
+        warn!("Retrying the scrobble in {}s", delay.as_secs());
+        self.scrobble_retry = Some((pending, Timeout::new(delay, &self.handle).unwrap()));
+    }
+
+    fn disable_lastfm(&mut self) {
+        self.lastfm_disabled = true;
+        self.stats.lock().unwrap().lastfm_failed = true;
+    }
+
+    fn in_skiplist(&self, meta: &TrackMeta) -> bool {
+        let (skiplist, track_id) = match (self.config.skiplist.as_ref(), self.current_track_id) {
+            (Some(skiplist), Some(track_id)) => (skiplist.lock().unwrap(), track_id),
+            _ => return false
+        };
+
+        let skipped = skiplist.contains(track_id, &meta.artist, &meta.title) ||
+                      skiplist.contains(track_id, &meta.credited_artist, &meta.title);
+        if skipped {
+            debug!("Not scrobbling {} - {}, it is in the skip-list", meta.credited_artist, meta.title);
+        }
+        skipped
+    }
+
     fn can_scrobble_track(&self) -> bool {
-        if self.current_track_scrobbled {
+        if self.current_track_scrobbled || self.current_track_inhibited || self.current_track_filtered ||
+           self.current_track_skipped || self.lastfm_disabled {
             return false
         }
 
@@ -175,20 +1282,233 @@ impl Scrobbler {
             None => {}
         }
 
-        match self.current_track_start {
-            Some(start_time) => {
-                let play_time = start_time.elapsed();
-                
-                if play_time > Duration::new(20, 0) {
-                    return true
+        // Already submitted, and will be again once the retry is due
+        if let Some((ref pending, _)) = self.scrobble_retry {
+            if pending.play == self.current_play {
+                return false
+            }
+        }
+
+        if self.in_cooldown() || self.in_small_context() || self.skipped_dj_context() {
+            return false
+        }
+
+        match self.counted_play_time() {
+            Some(play_time) => play_time > self.scrobble_threshold(),
+            None => false
+        }
+    }
+
+    fn in_cooldown(&self) -> bool {
+        let track_id = match self.current_track_id {
+            Some(track_id) => track_id,
+            None => return false
+        };
+
+        match self.recent_scrobbles.get(&track_id) {
+            Some(scrobbled_at) => scrobbled_at.elapsed() < self.config.cooldown,
+            None => false
+        }
+    }
+
+    fn in_small_context(&self) -> bool {
+        self.current_context_tracks < self.config.min_context_tracks
+    }
+
+    fn skipped_dj_context(&self) -> bool {
+        self.current_dj_context && self.config.dj_context_policy == DjContextPolicy::Skip
+    }
+
+    fn record_scrobble(&mut self, track_id: SpotifyId) {
+        if self.config.cooldown == Duration::from_secs(0) {
+            return
+        }
+
+        let cooldown = self.config.cooldown;
+        self.recent_scrobbles.retain(|_, scrobbled_at| scrobbled_at.elapsed() < cooldown);
+        self.recent_scrobbles.insert(track_id, Instant::now());
+    }
+
+    fn play_time(&self) -> Option<Duration> {
+        if self.current_track_id.is_none() {
+            return None
+        }
+
+        let segment_time = match self.current_segment {
+            Some((_, started_at)) => started_at.elapsed(),
+            None => Duration::from_secs(0)
+        };
+        Some(self.restored_play_time + self.current_track_played + segment_time)
+    }
+
+    /// Play time as compared against the threshold, less the configured lead-in allowance
+    fn counted_play_time(&self) -> Option<Duration> {
+        let offset = self.config.play_time_offset;
+        self.play_time().map(|play_time| play_time.checked_sub(offset).unwrap_or(Duration::from_secs(0)))
+    }
+
+    /// Notices the clock being corrected, or going wrong, and submits what was held back meanwhile.
+    /// The held back scrobbles are timestamped with the time their plays started.
+    fn check_clock(&mut self) {
+        let sane = clock_plausible();
+        if sane == self.clock_sane {
+            return
+        }
+
+        self.clock_sane = sane;
+        if !sane {
+            warn!("System clock jumped to {}s since the epoch; holding back scrobbles and checkpoints \
+                   until it is corrected", now_ms() / 1000);
+            return
+        }
+
+        if self.config.scrobble_on_exit_only {
+            info!("System clock looks right again");
+            return
+        }
+
+        info!("System clock looks right again, submitting {} held back scrobbles", self.deferred_scrobbles.len());
+        self.submit_deferred();
+    }
+
+    /// Submits the held back scrobbles in batches, as many as Last.fm takes per request, each
+    /// stamped with when its play started
+    fn submit_deferred(&mut self) {
+        let deferred = mem::replace(&mut self.deferred_scrobbles, Vec::new());
+        for batch in deferred.chunks(SCROBBLE_BATCH_MAX) {
+            let scrobbles = batch.iter().map(|held| {
+                let mut scrobble = held.meta.scrobble.clone();
+                scrobble.with_timestamp(held.started_secs());
+                scrobble
+            }).collect::<Vec<_>>();
+
+            if let Some(ref maloja) = self.maloja {
+                for held in batch {
+                    maloja.scrobble(&held.meta);
                 }
+            }
 
-                false
-            },
-            _ => false
+            info!("Scrobbling a batch of {}", scrobbles.len());
+            let status = match self.scrobbler.scrobble_batch(&ScrobbleBatch::from(scrobbles)) {
+                Ok(_) => {
+                    let mut stats = self.stats.lock().unwrap();
+                    stats.scrobbles += batch.len() as u64;
+                    stats.last_scrobble = Some(Instant::now());
+                    "scrobbled"
+                }
+                Err(err) => {
+                    error!("Scrobbling error: {:?}", err);
+                    "failed"
+                }
+            };
+            for held in batch {
+                self.record_in_db(&held.meta, &held.meta.artist, status);
+            }
+        }
+    }
+
+    fn checkpoint(&self) {
+        if !self.clock_sane {
+            return
+        }
+
+        let cache = match self.session.cache() {
+            Some(cache) => cache,
+            None => return
+        };
+        let (track_id, play_time) = match (self.current_track_id, self.play_time()) {
+            (Some(track_id), Some(play_time)) => (track_id, play_time),
+            _ => return
+        };
+
+        let checkpoint = Checkpoint {
+            track_id: track_id.to_base16(),
+            play_time_secs: play_time.as_secs(),
+            scrobbled: self.current_track_scrobbled,
+            saved_at_ms: now_ms() as u64,
+        };
+        cache.save_scrobbler_state(&serde_json::to_string(&checkpoint).unwrap());
+    }
+
+    /// Picks up where we left off if the first track seen after starting is the checkpointed one
+    fn restore_checkpoint(&mut self, track_id: SpotifyId) {
+        let checkpoint = match self.restored_checkpoint.take() {
+            Some(checkpoint) => checkpoint,
+            None => return
+        };
+
+        if !self.clock_sane {
+            return
+        }
+
+        if !checkpoint.applies_to(track_id, now_ms() as u64) {
+            return
+        }
+
+        info!("Restoring {}s of play time for track {} from before restart",
+              checkpoint.play_time_secs, checkpoint.track_id);
+        self.restored_play_time = Duration::from_secs(checkpoint.play_time_secs);
+        self.current_track_scrobbled = checkpoint.scrobbled;
+    }
+
+    /// How long ago the current play began, counting play time from before a restart
+    fn since_play_start(&self) -> Duration {
+        self.current_play_started.elapsed() + self.restored_play_time
+    }
+
+    fn play_started_secs(&self) -> u64 {
+        play_start_secs(now_ms() as u64 / 1000, self.since_play_start())
+    }
+
+    fn scrobble_threshold(&self) -> Duration {
+        match self.current_track_meta {
+            Some(ref meta) => scrobble_threshold(meta.duration),
+            None => Duration::from_secs(SCROBBLE_THRESHOLD_MAX_SECS)
         }
     }
 
+    fn log_threshold_decision(&self) {
+        let track_id = match self.current_track_id {
+            Some(track_id) => track_id,
+            None => return
+        };
+
+        let play_time = self.counted_play_time().unwrap_or(Duration::from_secs(0));
+        let threshold = self.scrobble_threshold();
+        let scrobbled = self.current_track_scrobbled || self.scrobble_future.is_some();
+
+        let reason = if scrobbled {
+            ""
+        } else if self.current_track_meta.is_none() {
+            ", reason: no track metadata"
+        } else if self.current_track_skipped {
+            ", reason: in skip-list"
+        } else if self.current_track_inhibited {
+            ", reason: inhibited by command"
+        } else if self.current_track_filtered {
+            ", reason: rejected by filter command"
+        } else if self.in_cooldown() {
+            ", reason: scrobbled recently (cooldown)"
+        } else if self.in_small_context() {
+            ", reason: playback context has too few tracks"
+        } else if self.skipped_dj_context() {
+            ", reason: played from a DJ or radio context"
+        } else if play_time <= threshold {
+            ", reason: below threshold"
+        } else {
+            ", reason: submission failed"
+        };
+
+        let duration = match self.current_track_meta {
+            Some(ref meta) => format!("{}s", meta.duration.as_secs()),
+            None => String::from("unknown"),
+        };
+
+        info!("Track {} ended: played {}s, duration {}, threshold {}s, scrobbled: {}{}",
+              track_id.to_base16(), play_time.as_secs(), duration, threshold.as_secs(),
+              if scrobbled { "yes" } else { "no" }, reason);
+    }
+
 }
 
 impl Future for Scrobbler {
@@ -197,6 +1517,29 @@ impl Future for Scrobbler {
 
     fn poll(&mut self) -> Poll<Result<(), ()>, ()> {
 
+        while let Ok(Async::Ready(Some(()))) = self.checkpoint_timer.poll() {
+            self.check_clock();
+            self.checkpoint();
+
+            // Long tracks may go without state updates, so keep the session alive while playing
+            if self.current_segment.is_some() {
+                self.mark_listening();
+            }
+
+            // Play time is still counted, so the track can be scrobbled once metadata comes back
+            if let (true, Some(track_id)) = (self.meta_fetch_failed, self.current_track_id) {
+                self.meta_fetch_failed = false;
+                self.meta_fetch_future = self.get_track_meta(track_id);
+            }
+        }
+
+        let retry_auth = self.auth_failed_at
+            .map_or(false, |failed_at| failed_at.elapsed() >= Duration::from_secs(AUTH_RETRY_SECS));
+        if retry_auth {
+            self.auth_failed_at = None;
+            self.start_auth();
+        }
+
         match self.auth_future.poll() {
             Ok(Async::Ready(_)) => {
                 info!("Authenticated with Last.fm");
@@ -205,19 +1548,35 @@ impl Future for Scrobbler {
             Ok(Async::NotReady) => {
             },
             Err(err) => {
-                error!("Authentication error: {:?}", err);
+                error!("Authentication error, retrying in {}s: {:?}", AUTH_RETRY_SECS, err);
+                self.auth_future = future::empty().boxed();
+                self.auth_failed_at = Some(Instant::now());
                 return Err(())
             }
         }
 
+        self.check_pause_timeout();
+
+        let idle = self.scrobble_future.is_none();
+        let retry_due = match self.scrobble_retry {
+            Some((_, ref mut timeout)) => idle && timeout.poll().map(|result| result.is_ready()).unwrap_or(true),
+            None => false
+        };
+        if retry_due {
+            let (pending, _) = self.scrobble_retry.take().unwrap();
+            info!("Retrying scrobble (attempt {})", self.scrobble_failures + 1);
+            self.submit_scrobble(pending.play, pending.track_id, pending.meta);
+        }
+
         if self.can_scrobble_track() {
-            self.start_scrobble();
+            self.start_delayed_scrobble();
         }
 
         let mut track_scrobbled = false;
+        let mut scrobble_error = None;
         match self.scrobble_future {
-            Some(ref mut scrobble_future) => {
-                match scrobble_future.poll() {
+            Some(ref mut pending) => {
+                match pending.future.poll() {
                     Ok(Async::Ready(_)) => {
                         track_scrobbled = true;
                     },
@@ -226,7 +1585,7 @@ impl Future for Scrobbler {
                     },
                     Err(err) => {
                         error!("Scrobbling error: {:?}", err);
-                        return Err(())
+                        scrobble_error = Some(err);
                     }
                 }
             },
@@ -234,14 +1593,29 @@ impl Future for Scrobbler {
         }
 
         if track_scrobbled {
-            self.scrobble_future = None;
-            self.current_track_scrobbled = true;
+            let pending = self.scrobble_future.take().unwrap();
+            self.reauth_retried = false;
+            self.scrobble_failures = 0;
+            self.scrobble_succeeded(&pending);
+        }
+
+        if let Some(err) = scrobble_error {
+            let pending = self.scrobble_future.take().unwrap();
+            let retried = err.is_session_expired() && self.handle_expired_session(&pending);
+
+            // The scrobble only failed for good if it isn't being retried after logging in again
+            if !retried {
+                self.record_failure_in_db(&pending);
+                if !self.lastfm_disabled {
+                    self.schedule_scrobble_retry(pending);
+                }
+            }
+            return Err(())
         }
 
         match self.new_track_future.poll() {
             Ok(Async::Ready(_)) => {
                 self.new_track_future = future::empty().boxed();
-                self.current_track_scrobbled = false;
 
                 match self.current_track_id {
                     Some(track_id) => {
@@ -261,19 +1635,39 @@ impl Future for Scrobbler {
             }
         }
 
-        match self.meta_fetch_future.poll() {
-            Ok(Async::Ready(ref track)) => {
-                self.meta_fetch_future = future::empty().boxed();
-                self.now_playing_future = self.send_now_playing(track);
-                self.current_track_meta = Some(track.clone());
-            },
-            Ok(Async::NotReady) => {
-                
-            },
-            Err(err) => {
-                error!("Metadata fetch error: {:?}", err);
-                return Err(())
+        loop {
+            match self.meta_fetch_future.poll() {
+                Ok(Async::Ready(meta)) => {
+                    self.meta_fetch_future = Box::new(future::empty());
+                    self.stats.lock().unwrap().current_cover_url = meta.cover_url.clone();
+                    self.current_track_skipped = self.in_skiplist(&meta);
+                    self.current_track_meta = Some(meta);
+                    self.maybe_send_now_playing();
+                },
+                Ok(Async::NotReady) => {
+
+                },
+                Err(err) => {
+                    warn!("Metadata fetch error: {:?}", err);
+                    self.meta_fetch_future = Box::new(future::empty());
+
+                    // Metadata outages are usually transient, so retry a few times straight away,
+                    // then keep trying at the checkpoint interval until the track changes
+                    if let Some(track_id) = self.current_track_id {
+                        if self.meta_fetch_attempts < META_FETCH_ATTEMPTS {
+                            self.meta_fetch_future = self.get_track_meta(track_id);
+                            continue;
+                        }
+                    }
+
+                    if self.meta_fetch_attempts == META_FETCH_ATTEMPTS {
+                        error!("No metadata for current track, retrying every {}s", CHECKPOINT_INTERVAL_SECS);
+                    }
+                    self.meta_fetch_failed = true;
+                }
             }
+
+            break;
         }
 
         match self.now_playing_future.poll() {
@@ -293,3 +1687,256 @@ impl Future for Scrobbler {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json;
+
+    use metadata::{Album, ImageSize};
+    use core::util::{FileId, SpotifyId};
+    use super::{catalogue_track_meta, credited_play_time, play_start_secs, replayed, scrobble_retry_delay,
+                scrobble_threshold, scrobbled_after, starts_new_play, Checkpoint, PlaybackState, ScrobbleError,
+                CHECKPOINT_MAX_AGE_MS};
+
+    fn album() -> Album {
+        Album {
+            id: SpotifyId::from_raw(&[1; 16]),
+            name: String::from("Album"),
+            artists: Vec::new(),
+            tracks: Vec::new(),
+            covers: vec![FileId([2; 20])],
+            cover_sizes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn track_meta_with_album() {
+        let track_id = SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC");
+        let album = album();
+        let meta = catalogue_track_meta(track_id, "Title", 215000, "Artist", Some(&album), ImageSize::LARGE, &[]);
+
+        assert_eq!(meta.uri, "spotify:track:4uLU6hMCjMI75M1A2tKUQC");
+        assert_eq!(meta.artist, "Artist");
+        assert_eq!(meta.title, "Title");
+        assert_eq!(meta.album, "Album");
+        assert!(meta.cover_url.is_some());
+        assert_eq!(meta.duration, Duration::from_secs(215));
+    }
+
+    #[test]
+    fn track_meta_without_album() {
+        let track_id = SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC");
+        let separators = vec![String::from(" feat. ")];
+        let meta = catalogue_track_meta(track_id, "Title", 215000, "Artist feat. Guest", None, ImageSize::LARGE,
+                                        &separators);
+
+        // A failed album lookup still leaves everything needed for the scrobble
+        assert_eq!(meta.artist, "Artist");
+        assert_eq!(meta.credited_artist, "Artist feat. Guest");
+        assert_eq!(meta.title, "Title");
+        assert_eq!(meta.album, "");
+        assert_eq!(meta.cover_url, None);
+    }
+
+    #[test]
+    fn track_meta_without_duration() {
+        let track_id = SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC");
+        let meta = catalogue_track_meta(track_id, "Title", -1, "Artist", None, ImageSize::LARGE, &[]);
+
+        assert_eq!(meta.duration, Duration::from_secs(0));
+    }
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let track_id = SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC");
+        let checkpoint = Checkpoint {
+            track_id: track_id.to_base16(),
+            play_time_secs: 95,
+            scrobbled: false,
+            saved_at_ms: 1500000000000,
+        };
+
+        let restored: Checkpoint = serde_json::from_str(&serde_json::to_string(&checkpoint).unwrap()).unwrap();
+        assert_eq!(restored.track_id, checkpoint.track_id);
+        assert_eq!(restored.play_time_secs, 95);
+        assert_eq!(restored.scrobbled, false);
+        assert_eq!(restored.saved_at_ms, 1500000000000);
+        assert!(restored.applies_to(track_id, 1500000000000 + 30000));
+    }
+
+    #[test]
+    fn checkpoint_only_applies_to_recent_progress_on_the_same_track() {
+        let track_id = SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC");
+        let checkpoint = Checkpoint {
+            track_id: track_id.to_base16(),
+            play_time_secs: 95,
+            scrobbled: false,
+            saved_at_ms: 1500000000000,
+        };
+
+        assert!(!checkpoint.applies_to(SpotifyId::from_raw(&[1; 16]), 1500000000000));
+        assert!(!checkpoint.applies_to(track_id, 1500000000000 + CHECKPOINT_MAX_AGE_MS + 1));
+        // A clock that went backwards since still counts as recent
+        assert!(checkpoint.applies_to(track_id, 1400000000000));
+    }
+
+    #[test]
+    fn play_time_follows_the_reported_position() {
+        assert_eq!(credited_play_time(10000, 40000, Duration::from_secs(30)), Duration::from_secs(30));
+        // A pause in between only shows up as a smaller position change
+        assert_eq!(credited_play_time(10000, 20000, Duration::from_secs(60)), Duration::from_secs(10));
+        // Reports lagging the wall clock a little are taken as they are
+        assert_eq!(credited_play_time(10000, 41500, Duration::from_secs(30)), Duration::from_millis(31500));
+    }
+
+    #[test]
+    fn play_time_after_seeking_forward() {
+        // Jumping a minute ahead in 5s doesn't count the skipped part as listened
+        assert_eq!(credited_play_time(10000, 75000, Duration::from_secs(5)), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn play_time_after_seeking_back() {
+        assert_eq!(credited_play_time(120000, 30000, Duration::from_secs(5)), Duration::from_secs(5));
+    }
+
+    fn playing(track_index: u32, position_ms: u32) -> PlaybackState {
+        PlaybackState {
+            track_id: SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC"),
+            track_index: track_index,
+            context_tracks: 20,
+            dj_context: false,
+            position_ms: position_ms,
+            playing: true,
+            muted: false,
+            local_track: None,
+        }
+    }
+
+    #[test]
+    fn adjacent_duplicate_is_a_new_play() {
+        // The first copy played to the end, the second starts from the top
+        assert!(replayed(3, Some(180000), &playing(4, 0)));
+        assert!(replayed(3, Some(180000), &playing(4, 1200)));
+    }
+
+    #[test]
+    fn replayed_while_paused_is_a_new_play() {
+        assert!(replayed(3, None, &playing(4, 500)));
+    }
+
+    #[test]
+    fn moving_within_the_context_is_not_a_new_play() {
+        // Shuffling or queueing around the playing track changes its index mid-play
+        assert!(!replayed(3, Some(60000), &playing(11, 65000)));
+        assert!(!replayed(3, None, &playing(11, 65000)));
+    }
+
+    #[test]
+    fn jumping_back_to_another_copy_is_a_new_play() {
+        assert!(replayed(3, Some(60000), &playing(7, 20000)));
+    }
+
+    #[test]
+    fn invalidated_session_key_is_detected() {
+        let revoked = ScrobbleError::Lastfm(String::from(
+            "ScrobblerError { err_msg: \"Invalid session key - Please re-authenticate\" }"));
+        assert!(revoked.is_session_expired());
+    }
+
+    #[test]
+    fn other_errors_are_not_an_expired_session() {
+        let offline = ScrobbleError::Lastfm(String::from(
+            "ScrobblerError { err_msg: \"Operation failed - Most likely the backend service failed\" }"));
+        assert!(!offline.is_session_expired());
+
+        // Failing to log in in the first place isn't fixed by logging in again
+        let rejected = ScrobbleError::Auth(String::from("the Last.fm username or password is wrong"));
+        assert!(!rejected.is_session_expired());
+        assert!(!ScrobbleError::Metadata(String::from("track 00: timed out")).is_session_expired());
+    }
+
+    #[test]
+    fn scrobble_completing_after_a_track_change_leaves_the_new_track_scrobbleable() {
+        // The previous track qualified at the transition and its submission finished on the
+        // next poll, when play 2 had already started
+        assert!(!scrobbled_after(1, 2, false));
+    }
+
+    #[test]
+    fn scrobble_completing_after_a_track_change_keeps_the_restored_flag() {
+        // Play 2 was restored from a checkpoint that had it scrobbled already
+        assert!(scrobbled_after(1, 2, true));
+    }
+
+    #[test]
+    fn scrobble_completing_during_its_play_marks_it_scrobbled() {
+        assert!(scrobbled_after(2, 2, false));
+    }
+
+    #[test]
+    fn short_track_qualifies_at_half_its_duration() {
+        assert_eq!(scrobble_threshold(Duration::from_secs(200)), Duration::from_secs(100));
+    }
+
+    #[test]
+    fn long_track_qualifies_after_four_minutes() {
+        let threshold = scrobble_threshold(Duration::from_secs(1800));
+        assert_eq!(threshold, Duration::from_secs(240));
+        assert!(Duration::from_secs(241) > threshold);
+        assert!(threshold < Duration::from_secs(900));
+    }
+
+    #[test]
+    fn unknown_duration_falls_back_to_four_minutes() {
+        assert_eq!(scrobble_threshold(Duration::from_secs(0)), Duration::from_secs(240));
+    }
+
+    #[test]
+    fn track_change_part_way_in_is_a_new_play() {
+        // A gapless transition is first reported after the next track has been playing a while
+        let previous = SpotifyId::from_raw(&[1; 16]);
+        let state = playing(4, 1500);
+        assert!(starts_new_play(Some(previous), 3, Some(170000), &state));
+        assert!(!starts_new_play(Some(state.track_id), 4, Some(0), &state));
+    }
+
+    #[test]
+    fn checkpoint_survives_a_small_clock_step_forward() {
+        let track_id = SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC");
+        let checkpoint = Checkpoint {
+            track_id: track_id.to_base16(),
+            play_time_secs: 95,
+            scrobbled: false,
+            saved_at_ms: 1500000000000,
+        };
+
+        // NTP correcting the clock by half a minute while restarting
+        assert!(checkpoint.applies_to(track_id, 1500000000000 + 30000));
+    }
+
+    #[test]
+    fn play_time_ignores_the_wall_clock_after_a_long_uptime() {
+        // Elapsed time comes from the monotonic clock, which doesn't jump, and a year of it
+        // doesn't overflow
+        let year = Duration::from_secs(365 * 24 * 60 * 60);
+        assert_eq!(credited_play_time(0, 3000, year), Duration::from_millis(3000));
+    }
+
+    #[test]
+    fn delayed_scrobble_is_stamped_with_the_play_start() {
+        // Submitted 30s after passing a 100s threshold
+        assert_eq!(play_start_secs(1500000130, Duration::from_secs(130)), 1500000000);
+    }
+
+    #[test]
+    fn failed_scrobble_retries_back_off_up_to_a_minute() {
+        assert_eq!(scrobble_retry_delay(1), Duration::from_secs(2));
+        assert_eq!(scrobble_retry_delay(2), Duration::from_secs(4));
+        assert_eq!(scrobble_retry_delay(5), Duration::from_secs(32));
+        assert_eq!(scrobble_retry_delay(6), Duration::from_secs(60));
+        assert_eq!(scrobble_retry_delay(40), Duration::from_secs(60));
+    }
+}
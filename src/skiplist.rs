@@ -0,0 +1,78 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use core::util::SpotifyId;
+
+/// Tracks that are never scrobbled, listed one per line as a `spotify:track:` URI, a base16
+/// track id or an `Artist - Title` pair. Blank lines and lines starting with `#` are ignored.
+#[derive(Debug)]
+pub struct SkipList {
+    path: PathBuf,
+    track_ids: HashSet<SpotifyId>,
+    tracks: HashSet<String>,
+}
+
+impl SkipList {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<SkipList> {
+        let mut skiplist = SkipList {
+            path: path.as_ref().to_owned(),
+            track_ids: HashSet::new(),
+            tracks: HashSet::new(),
+        };
+
+        try!(skiplist.reload());
+        Ok(skiplist)
+    }
+
+    /// Re-reads the file, keeping the current entries if it can't be read
+    pub fn reload(&mut self) -> io::Result<()> {
+        let file = try!(File::open(&self.path));
+
+        let mut track_ids = HashSet::new();
+        let mut tracks = HashSet::new();
+        for line in BufReader::new(file).lines() {
+            let line = try!(line);
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+
+            if let Some(track_id) = parse_track_id(line) {
+                track_ids.insert(track_id);
+            } else if line.contains(" - ") {
+                tracks.insert(line.to_lowercase());
+            } else {
+                warn!("Ignoring unrecognised skip-list entry {:?} in {}", line, self.path.display());
+            }
+        }
+
+        info!("Loaded {} skip-list entries from {}", track_ids.len() + tracks.len(), self.path.display());
+        self.track_ids = track_ids;
+        self.tracks = tracks;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn contains(&self, track_id: SpotifyId, artist: &str, title: &str) -> bool {
+        self.track_ids.contains(&track_id) ||
+            self.tracks.contains(&format!("{} - {}", artist, title).to_lowercase())
+    }
+}
+
+fn parse_track_id(entry: &str) -> Option<SpotifyId> {
+    if entry.starts_with("spotify:track:") {
+        let id = &entry["spotify:track:".len()..];
+        if id.len() == 22 && id.chars().all(|c| c.is_digit(36)) {
+            return Some(SpotifyId::from_base62(id))
+        }
+    } else if entry.len() == 32 && entry.chars().all(|c| c.is_digit(16) && !c.is_uppercase()) {
+        return Some(SpotifyId::from_base16(entry))
+    }
+
+    None
+}
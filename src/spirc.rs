@@ -3,10 +3,12 @@ use futures::stream::BoxStream;
 use futures::sync::mpsc;
 use futures::{Future, Stream, Sink, Async, Poll};
 use protobuf::{self, Message};
+use std::sync::{Arc, Mutex};
+use tokio_core::reactor::Handle;
 
 use core::config::ConnectConfig;
 use core::mercury::MercuryError;
-use scrobbler::{Scrobbler, ScrobblerConfig};
+use scrobbler::{self, HeldScrobble, LocalTrack, PlaybackState, Scrobbler, ScrobblerConfig, ScrobblerStats};
 use core::session::Session;
 use core::util::{now_ms, SpotifyId, SeqGenerator};
 use core::version;
@@ -28,11 +30,19 @@ pub struct SpircTask {
 
     shutdown: bool,
     session: Session,
+    trace_events: bool,
 
-    scrobbler: Scrobbler
+    active_device: Option<String>,
+    scrobbler_stats: Arc<Mutex<ScrobblerStats>>,
+    scrobbler: Option<Scrobbler>
 }
 
 pub enum SpircCommand {
+    Play,
+    Pause,
+    Next,
+    Prev,
+    Volume(u16),
     Shutdown
 }
 
@@ -109,7 +119,8 @@ fn initial_device_state(config: ConnectConfig, volume: u16) -> DeviceState {
 }
 
 impl Spirc {
-    pub fn new(config: ConnectConfig, session: Session, scrobbler_config: ScrobblerConfig)
+    pub fn new(config: ConnectConfig, session: Session, scrobbler_config: Option<ScrobblerConfig>,
+               scrobbler_stats: Arc<Mutex<ScrobblerStats>>, handle: Handle)
         -> (Spirc, SpircTask)
     {
         debug!("new Spirc[{}]", session.session_id());
@@ -131,10 +142,13 @@ impl Spirc {
 
         let (cmd_tx, cmd_rx) = mpsc::unbounded();
 
-        let volume = 0xFFFF;
+        let trace_events = config.trace_events;
+        let volume = config.initial_volume;
         let device = initial_device_state(config, volume);
 
-        let scrobbler = Scrobbler::new(scrobbler_config, session.clone());
+        let scrobbler = scrobbler_config.map(|scrobbler_config| {
+            Scrobbler::new(scrobbler_config, session.clone(), scrobbler_stats.clone(), handle)
+        });
 
         let mut task = SpircTask {
             sequence: SeqGenerator::new(1),
@@ -150,7 +164,10 @@ impl Spirc {
 
             shutdown: false,
             session: session.clone(),
+            trace_events: trace_events,
 
+            active_device: None,
+            scrobbler_stats: scrobbler_stats,
             scrobbler: scrobbler
         };
 
@@ -163,6 +180,21 @@ impl Spirc {
         (spirc, task)
     }
 
+    pub fn play(&self) {
+        let _ = mpsc::UnboundedSender::send(&self.commands, SpircCommand::Play);
+    }
+    pub fn pause(&self) {
+        let _ = mpsc::UnboundedSender::send(&self.commands, SpircCommand::Pause);
+    }
+    pub fn next(&self) {
+        let _ = mpsc::UnboundedSender::send(&self.commands, SpircCommand::Next);
+    }
+    pub fn prev(&self) {
+        let _ = mpsc::UnboundedSender::send(&self.commands, SpircCommand::Prev);
+    }
+    pub fn volume(&self, volume: u16) {
+        let _ = mpsc::UnboundedSender::send(&self.commands, SpircCommand::Volume(volume));
+    }
     pub fn shutdown(&self) {
         let _ = mpsc::UnboundedSender::send(&self.commands, SpircCommand::Shutdown);
     }
@@ -177,13 +209,17 @@ impl Future for SpircTask {
             let mut progress = false;
 
             if !self.shutdown {
-                match self.subscription.poll().unwrap() {
-                    Async::Ready(Some(frame)) => {
+                match self.subscription.poll() {
+                    Ok(Async::Ready(Some(frame))) => {
                         progress = true;
                         self.handle_frame(frame);
                     }
-                    Async::Ready(None) => panic!("subscription terminated"),
-                    Async::NotReady => (),
+                    // The session ends its subscriptions when the connection drops, Main reconnects
+                    Ok(Async::Ready(None)) | Err(_) => {
+                        error!("Spirc subscription ended, the connection to Spotify was lost");
+                        return Ok(Async::Ready(()));
+                    }
+                    Ok(Async::NotReady) => (),
                 }
 
                 match self.commands.poll().unwrap() {
@@ -195,20 +231,28 @@ impl Future for SpircTask {
                     Async::NotReady => (),
                 }
 
-                match self.scrobbler.poll() {
-                    Ok(Async::Ready(_)) => {
-                        progress = true;
-                    },
-                    Ok(Async::NotReady) => {
-
-                    },
-                    Err(err) => {
-                        error!("Scrobbler error: {:?}", err);
+                if let Some(ref mut scrobbler) = self.scrobbler {
+                    match scrobbler.poll() {
+                        Ok(Async::Ready(_)) => {
+                            progress = true;
+                        },
+                        Ok(Async::NotReady) => {
+
+                        },
+                        Err(err) => {
+                            error!("Scrobbler error: {:?}", err);
+                        }
                     }
                 }
             }
 
-            let poll_sender = self.sender.poll_complete().unwrap();
+            let poll_sender = match self.sender.poll_complete() {
+                Ok(poll_sender) => poll_sender,
+                Err(_) => {
+                    error!("Could not send to Spotify, the connection was lost");
+                    return Ok(Async::Ready(()));
+                }
+            };
 
             // Only shutdown once we've flushed out all our messages
             if self.shutdown && poll_sender.is_ready() {
@@ -223,9 +267,38 @@ impl Future for SpircTask {
 }
 
 impl SpircTask {
+    pub fn take_held_scrobbles(&mut self) -> Vec<HeldScrobble> {
+        self.scrobbler.as_mut().map(|scrobbler| scrobbler.take_deferred()).unwrap_or_default()
+    }
+
+    pub fn restore_held_scrobbles(&mut self, scrobbles: Vec<HeldScrobble>) {
+        if let Some(ref mut scrobbler) = self.scrobbler {
+            scrobbler.restore_deferred(scrobbles);
+        }
+    }
+
     fn handle_command(&mut self, cmd: SpircCommand) {
         match cmd {
+            SpircCommand::Play => {
+                self.send_to_active_device(MessageType::kMessageTypePlay, None);
+            }
+            SpircCommand::Pause => {
+                self.send_to_active_device(MessageType::kMessageTypePause, None);
+            }
+            SpircCommand::Next => {
+                self.send_to_active_device(MessageType::kMessageTypeNext, None);
+            }
+            SpircCommand::Prev => {
+                self.send_to_active_device(MessageType::kMessageTypePrev, None);
+            }
+            SpircCommand::Volume(volume) => {
+                self.send_to_active_device(MessageType::kMessageTypeVolume, Some(volume));
+            }
             SpircCommand::Shutdown => {
+                if let Some(ref mut scrobbler) = self.scrobbler {
+                    scrobbler.flush();
+                }
+
                 CommandSender::new(self, MessageType::kMessageTypeGoodbye).send();
                 self.shutdown = true;
                 self.commands.close();
@@ -252,21 +325,64 @@ impl SpircTask {
             }
 
             MessageType::kMessageTypeNotify => {
+                if self.trace_events {
+                    self.trace_frame(&frame);
+                }
+
                 // Inactive devices won't be playing anything, so we don't need to scrobble it
                 if !frame.get_device_state().get_is_active() {
+                    if self.active_device.as_ref().map(String::as_str) == Some(frame.get_ident()) {
+                        self.active_device = None;
+                        if let Some(ref mut scrobbler) = self.scrobbler {
+                            scrobbler.playback_stopped();
+                        }
+                        let mut stats = self.scrobbler_stats.lock().unwrap();
+                        stats.active = false;
+                        stats.playing = false;
+                    }
                     return ();
                 }
 
                 //info!("{:?}", frame);
                 let state = frame.get_state();
+                self.active_device = Some(frame.get_ident().to_owned());
+                {
+                    let mut stats = self.scrobbler_stats.lock().unwrap();
+                    stats.active = true;
+                    stats.playing = state.get_status() == PlayStatus::kPlayStatusPlay;
+                    stats.position_ms = state.get_position_ms();
+                    stats.position_measured_at = state.get_position_measured_at();
+                }
+
                 let playing_index = state.get_playing_track_index();
                 let tracks = state.get_track();
                 if tracks.len() > 0 {
                     let playing_track_ref = state.get_track()[playing_index as usize].clone();
-                    let playing_track_spotify_id = SpotifyId::from_raw(playing_track_ref.get_gid());
-                    let force_new_track = state.get_position_ms() == 0;
 
-                    self.scrobbler.update_current_track(playing_track_spotify_id, force_new_track);
+                    // Local files have no gid, only a URI carrying their tags
+                    let local_track = LocalTrack::from_uri(playing_track_ref.get_uri());
+                    let playing_track_spotify_id = if local_track.is_some() {
+                        LocalTrack::track_id(playing_track_ref.get_uri())
+                    } else if playing_track_ref.get_gid().len() == 16 {
+                        SpotifyId::from_raw(playing_track_ref.get_gid())
+                    } else {
+                        debug!("Ignoring track without an id: {:?}", playing_track_ref.get_uri());
+                        return;
+                    };
+                    let playback = PlaybackState {
+                        track_id: playing_track_spotify_id,
+                        track_index: playing_index,
+                        context_tracks: tracks.len(),
+                        dj_context: scrobbler::is_dj_context(state.get_context_uri()),
+                        position_ms: state.get_position_ms(),
+                        playing: state.get_status() == PlayStatus::kPlayStatusPlay,
+                        muted: frame.get_device_state().get_volume() == 0,
+                        local_track: local_track,
+                    };
+
+                    if let Some(ref mut scrobbler) = self.scrobbler {
+                        scrobbler.update_current_track(&playback);
+                    }
                     info!("Relevant SPIRC frame; Current track Spotify ID: {:?}", playing_track_spotify_id);
                 }
                 
@@ -275,6 +391,45 @@ impl SpircTask {
         }
     }
 
+    // We never play anything ourselves, so playback commands go to whichever device is playing
+    fn send_to_active_device(&mut self, cmd: MessageType, volume: Option<u16>) {
+        let recipient = match self.active_device.clone() {
+            Some(recipient) => recipient,
+            None => {
+                warn!("No active device to send {:?} to", cmd);
+                return;
+            }
+        };
+
+        let mut cs = CommandSender::new(self, cmd).recipient(&recipient);
+        if let Some(volume) = volume {
+            cs = cs.volume(volume);
+        }
+        cs.send();
+    }
+
+    fn trace_frame(&self, frame: &Frame) {
+        let state = frame.get_state();
+        let track = state.get_track()
+            .get(state.get_playing_track_index() as usize)
+            .map(|track| if track.get_gid().len() == 16 {
+                SpotifyId::from_raw(track.get_gid()).to_base16()
+            } else {
+                // Local files and some queued tracks only have a URI
+                String::from(track.get_uri())
+            });
+
+        debug!("Spirc state from {}: active={} status={:?} track={:?} index={} position={}ms measured_at={} context={}",
+               frame.get_ident(),
+               frame.get_device_state().get_is_active(),
+               state.get_status(),
+               track,
+               state.get_playing_track_index(),
+               state.get_position_ms(),
+               state.get_position_measured_at(),
+               state.get_context_uri());
+    }
+
     fn hello(&mut self) {
         CommandSender::new(self, MessageType::kMessageTypeHello).send();
     }
@@ -323,6 +478,11 @@ impl<'a> CommandSender<'a> {
         self
     }
 
+    fn volume(mut self, volume: u16) -> CommandSender<'a> {
+        self.frame.set_volume(volume as u32);
+        self
+    }
+
     #[allow(dead_code)]
     fn state(mut self, state: protocol::spirc::State) -> CommandSender<'a> {
         self.frame.set_state(state);
@@ -0,0 +1,79 @@
+use futures::Future;
+use hyper::{Client, Method, Request, Uri};
+use hyper::client::HttpConnector;
+use hyper::header::{ContentLength, ContentType};
+use serde_json;
+use tokio_core::reactor::Handle;
+
+use core::util::now_ms;
+use scrobbler::TrackMeta;
+
+#[derive(Serialize, Debug)]
+struct Event<'a> {
+    event: &'a str,
+    backend: &'a str,
+    track_uri: &'a str,
+    artist: &'a str,
+    title: &'a str,
+    album: &'a str,
+    cover_url: Option<&'a String>,
+    session: u64,
+    timestamp: u64,
+}
+
+/// Posts scrobble events to a user supplied URL. Delivery is best-effort: failures are logged
+/// and the event is dropped.
+pub struct Webhook {
+    client: Client<HttpConnector>,
+    uri: Uri,
+    handle: Handle,
+}
+
+impl Webhook {
+    pub fn new(uri: Uri, handle: &Handle) -> Webhook {
+        Webhook {
+            client: Client::new(handle),
+            uri: uri,
+            handle: handle.clone(),
+        }
+    }
+
+    /// `artist` is passed separately as scrobbles and now-playing credit artists differently
+    pub fn send(&self, event: &str, artist: &str, meta: &TrackMeta, session: u64) {
+        let body = serde_json::to_string(&Event {
+            event: event,
+            backend: "lastfm",
+            track_uri: &meta.uri,
+            artist: artist,
+            title: &meta.title,
+            album: &meta.album,
+            cover_url: meta.cover_url.as_ref(),
+            session: session,
+            timestamp: now_ms() as u64 / 1000,
+        }).unwrap();
+
+        let mut request = Request::new(Method::Post, self.uri.clone());
+        request.headers_mut().set(ContentType::json());
+        request.headers_mut().set(ContentLength(body.len() as u64));
+        request.set_body(body);
+
+        let uri = self.uri.clone();
+        let event = event.to_owned();
+        let delivery = self.client.request(request).then(move |result| {
+            match result {
+                Ok(ref response) if response.status().is_success() => {
+                    debug!("Delivered {} webhook to {}", event, uri);
+                }
+                Ok(response) => {
+                    warn!("Webhook {} rejected {} event: {}", uri, event, response.status());
+                }
+                Err(err) => {
+                    warn!("Could not deliver {} webhook to {}: {}", event, uri, err);
+                }
+            }
+            Ok(())
+        });
+
+        self.handle.spawn(delivery);
+    }
+}